@@ -0,0 +1,74 @@
+#! /bin/bash
+#
+# Request a diagnostics report (boot count, reset history and last crash),
+# or read back the last one.  The report is produced at the next power up.
+# Both live in the second half of the deck config sector, so the deck is left
+# as it is.
+#
+#   diagnostics.sh </dev/sdX>      request a report
+#   diagnostics.sh -r </dev/sdX>   read the report
+REPORT=n
+[ "$1" = "-r" ] && { REPORT=y; shift 1; }
+[ -z "$1" ] && { echo "usage: $0 [-r] </dev/sdX>"; exit 1; }
+DEVICE=$1
+# Use LBA_SIZE=512 for firmware built with the emulate-512b-blocks feature
+LBA_SIZE=${LBA_SIZE:-4096}
+SECTOR_SIZE=4096
+# Requests and reports live in the deck config sector, the last one on the
+# disk, from REPORT_OFFSET on
+FLASH_SIZE=$(sudo blockdev --getsize64 ${DEVICE})
+CONFIG_LBA=$(( (FLASH_SIZE - SECTOR_SIZE) / LBA_SIZE ))
+REPORT_OFFSET=2048
+T=/tmp/diagnostics.bin
+
+CAUSES=(? "POR/BOR" Pin Software IWDG WWDG "Low power" "Option bytes" Firewall Unknown)
+CHARGE=(Dead Critical VeryLow Low Medium High Full)
+
+le32() {
+    printf "%b" "$(printf '\\x%02x' $(( $1 & 0xff )) $(( ($1 >> 8) & 0xff )) \
+        $(( ($1 >> 16) & 0xff )) $(( ($1 >> 24) & 0xff )))"
+}
+
+# Offsets are from the start of the report
+word() {
+    od -A n -t u4 -j $(( REPORT_OFFSET + $1 )) -N 4 $T | tr -d ' '
+}
+
+byte() {
+    od -A n -t u1 -j $(( REPORT_OFFSET + $1 )) -N 1 $T | tr -d ' '
+}
+
+if [ "${REPORT}" = 'y' ]
+then
+    sudo sg_dd blk_sgio=1 if=${DEVICE} of=$T bs=${LBA_SIZE} skip=${CONFIG_LBA} count=$(( SECTOR_SIZE / LBA_SIZE ))
+    [ "$(word 0)" = $(( 0xd1a60002 )) ] || { echo "No diagnostics report"; exit 2; }
+    echo "Boots: $(word 4)"
+    for i in $(seq 0 $(( $(word 8) - 1 )))
+    do
+        OFFSET=$(( 12 + 8 * i ))
+        CAUSE=$(byte $(( OFFSET + 4 )))
+        WAKE=$(byte $(( OFFSET + 5 )))
+        LEVEL=$(byte $(( OFFSET + 6 )))
+        [ ${WAKE} = 255 ] && WAKE=?
+        echo "  boot $(word ${OFFSET}): reset by ${CAUSES[${CAUSE}]:-?}," \
+            "wake up reason ${WAKE}, charge ${CHARGE[${LEVEL}]:-?}"
+    done
+    if [ "$(word 256)" = $(( 0xc4a50001 )) ]
+    then
+        if [ "$(word 260)" = 1 ]
+        then
+            FILE=$(dd if=$T bs=1 skip=$(( REPORT_OFFSET + 268 )) count=32 status=none | tr -d '\0')
+            MESSAGE=$(dd if=$T bs=1 skip=$(( REPORT_OFFSET + 300 )) count=64 status=none | tr -d '\0')
+            echo "Last crash: panic at ${FILE}:$(word 264): ${MESSAGE}"
+        else
+            printf "Last crash: HardFault pc 0x%08x lr 0x%08x xpsr 0x%08x\n" \
+                $(word 388) $(word 384) $(word 392)
+        fi
+    fi
+    exit 0
+fi
+
+sudo sg_dd blk_sgio=1 if=${DEVICE} of=$T bs=${LBA_SIZE} skip=${CONFIG_LBA} count=$(( SECTOR_SIZE / LBA_SIZE ))
+le32 $(( 0xd1a60001 )) | dd of=$T bs=1 seek=${REPORT_OFFSET} conv=notrunc status=none
+sudo sg_dd blk_sgio=1 if=$T of=${DEVICE} bs=${LBA_SIZE} seek=${CONFIG_LBA} count=$(( SECTOR_SIZE / LBA_SIZE ))
+echo "Diagnostics requested, wait a second for it to be flushed, then power cycle"
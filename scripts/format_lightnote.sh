@@ -7,13 +7,20 @@ set -x
 [ -z "$1" ] && { echo "usage: $0 [-v] </dev/sdX>"; exit 1; }
 
 DEVICE=$1
-FLASH_SIZE=$((16 * 1024 * 1024 ))
-LBA_SIZE=4096
+# Size as reported by the device, which depends on the flash part fitted
+FLASH_SIZE=$(sudo blockdev --getsize64 ${DEVICE})
+# Use LBA_SIZE=512 for firmware built with the emulate-512b-blocks feature
+LBA_SIZE=${LBA_SIZE:-4096}
 FLASH_SIZE_LBA=$(( FLASH_SIZE / LBA_SIZE))
 
 dd if=/dev/zero of=disk.img bs=${LBA_SIZE} count=$((FLASH_SIZE_LBA)) &> /dev/null
-# -S sizecode for 4096 is 5
-mformat -F -S 5 -i disk.img -v lightnote ::
+if [ "${LBA_SIZE}" = 4096 ]
+then
+    # -S sizecode for 4096 is 5
+    mformat -F -S 5 -i disk.img -v lightnote ::
+else
+    mformat -F -i disk.img -v lightnote ::
+fi
 
 # Write all 0xff to erase entire flash
 sudo sg_write_same --10 --ff --num 0 --lba 0 --xferlen 1 ${DEVICE}
@@ -0,0 +1,55 @@
+#! /bin/bash
+#
+# Request a flash self-test, or read back the report of the last one.
+# The test runs at the next power up and DESTROYS the contents of the
+# sectors it covers (and the deck config), so reformat afterwards.
+#
+#   self_test.sh </dev/sdX> [first_sector] [count]   request a test
+#   self_test.sh -r </dev/sdX>                        read the report
+#
+# count=0 (the default) tests up to the last sector the host sees.  Firmware
+# built with wear-leveling skips the first 128 sectors, the report says which
+# range was actually tested.
+REPORT=n
+[ "$1" = "-r" ] && { REPORT=y; shift 1; }
+[ -z "$1" ] && { echo "usage: $0 [-r] </dev/sdX> [first_sector] [count]"; exit 1; }
+DEVICE=$1
+FIRST=${2:-0}
+COUNT=${3:-0}
+# Use LBA_SIZE=512 for firmware built with the emulate-512b-blocks feature
+LBA_SIZE=${LBA_SIZE:-4096}
+SECTOR_SIZE=4096
+# Requests and reports live in the deck config sector, the last one on the disk
+FLASH_SIZE=$(sudo blockdev --getsize64 ${DEVICE})
+CONFIG_LBA=$(( (FLASH_SIZE - SECTOR_SIZE) / LBA_SIZE ))
+T=/tmp/self_test.bin
+
+le32() {
+    printf "%b" "$(printf '\\x%02x' $(( $1 & 0xff )) $(( ($1 >> 8) & 0xff )) \
+        $(( ($1 >> 16) & 0xff )) $(( ($1 >> 24) & 0xff )))"
+}
+
+word() {
+    od -A n -t u4 -j $1 -N 4 $T | tr -d ' '
+}
+
+if [ "${REPORT}" = 'y' ]
+then
+    sudo sg_dd blk_sgio=1 if=${DEVICE} of=$T bs=${LBA_SIZE} skip=${CONFIG_LBA} count=$(( SECTOR_SIZE / LBA_SIZE ))
+    [ "$(word 0)" = $(( 0x5e1f7e58 )) ] || { echo "No self-test report"; exit 2; }
+    BAD=$(word 12)
+    echo "Tested $(word 8) sectors from $(word 4), ${BAD} bad"
+    for i in $(seq 0 $(( BAD - 1 )))
+    do
+        OFFSET=$(( 16 + 4 * i ))
+        [ ${OFFSET} -ge ${SECTOR_SIZE} ] && { echo "..."; break; }
+        echo "  bad sector $(word ${OFFSET})"
+    done
+    exit 0
+fi
+
+tr '\0' '\377' < /dev/zero | head -c ${SECTOR_SIZE} > $T
+{ le32 $(( 0x5e1f7e57 )); le32 ${FIRST}; le32 ${COUNT}; } | \
+    dd of=$T conv=notrunc status=none
+sudo sg_dd blk_sgio=1 if=$T of=${DEVICE} bs=${LBA_SIZE} seek=${CONFIG_LBA} count=$(( SECTOR_SIZE / LBA_SIZE ))
+echo "Self-test of sectors ${FIRST}+${COUNT} requested, wait a second for it to be flushed, then power cycle"
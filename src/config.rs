@@ -1,9 +1,8 @@
 use core::{convert::TryInto, fmt::Display};
 
-use cortex_m_semihosting::hprintln;
-use usbd_dfu::{DFUMemError, DFUMemIO};
+use usbd_scsi::BlockDeviceError;
 
-use crate::flash::SpiFlash;
+use crate::{flash::FLASH_SECTOR_SIZE, spi_bus::SharedFlash};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum QAType {
@@ -12,14 +11,13 @@ pub(crate) enum QAType {
     RawImage = 2,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) enum FlashConfigError {
     InvalidFlashConfigMagicId,
     InvalidQAType,
     FailedToReadFlash,
 }
 
-const CONFIG_SECTOR_ADDRESS: u32 = 0xff_f000;
 const MAGIC_ID_OFFSET: usize = 0x0;
 const PAGE_SIZE_OFFSET: usize = 0x4;
 const NUM_PAGES_OFFSET: usize = 0x6;
@@ -72,16 +70,24 @@ impl Display for QAType {
     }
 }
 
-impl From<DFUMemError> for FlashConfigError {
-    fn from(_: DFUMemError) -> Self {
+impl From<BlockDeviceError> for FlashConfigError {
+    fn from(_: BlockDeviceError) -> Self {
         FlashConfigError::FailedToReadFlash
     }
 }
 
+// The config lives in the last sector the host can see (0xff_f000 on a 16 MiB
+// flash unless some sectors are reserved for journaling, CRCs or wear
+// leveling)
+pub(crate) fn config_sector(flash: &mut impl SharedFlash) -> u32 {
+    flash.with_flash(|flash| flash.visible_sectors()) - 1
+}
+
 impl FlashConfig {
-    pub(crate) fn from_flash(flash: &mut SpiFlash) -> Result<Self, FlashConfigError> {
-        let addr = CONFIG_SECTOR_ADDRESS + MAGIC_ID_OFFSET as u32;
-        // let buf = flash.read(addr, CONFIG_SIZE)?;
+    pub(crate) fn from_flash(flash: &mut impl SharedFlash) -> Result<Self, FlashConfigError> {
+        let addr = config_sector(flash) * FLASH_SECTOR_SIZE as u32 + MAGIC_ID_OFFSET as u32;
+        let mut buf = [0u8; CONFIG_SIZE];
+        flash.read(addr, &mut buf)?;
         let magic_id =
             u32::from_le_bytes(buf[MAGIC_ID_OFFSET..PAGE_SIZE_OFFSET].try_into().unwrap());
         if magic_id != 0x23571113 {
@@ -112,12 +118,27 @@ impl FlashConfig {
             a_type,
         })
     }
+
+    // Bytes from the start of the flash taken up by the cards
+    pub(crate) fn deck_size(&self) -> u32 {
+        self.page_size as u32 * self.num_pages
+    }
+
+    // Address of the card that follows the one at `display_addr`, wrapping
+    // around to the first card at the end of the deck
+    pub(crate) fn next_page_addr(&self, display_addr: u32) -> u32 {
+        let deck_size = self.deck_size();
+        if deck_size == 0 {
+            return 0;
+        }
+        (display_addr + self.page_size as u32) % deck_size
+    }
 }
 
 #[allow(dead_code)]
 pub(crate) fn dump(config: &FlashConfig) {
-    hprintln!("page_size: {}", config.page_size).ok();
-    hprintln!("num_pages: {}", config.num_pages).ok();
-    hprintln!("q type: {}", config.q_type).ok();
-    hprintln!("a type: {}", config.a_type).ok();
+    defmt::info!("page_size: {}", config.page_size);
+    defmt::info!("num_pages: {}", config.num_pages);
+    defmt::info!("q type: {}", defmt::Display2Format(&config.q_type));
+    defmt::info!("a type: {}", defmt::Display2Format(&config.a_type));
 }
@@ -0,0 +1,223 @@
+// Panic and HardFault handlers.  Both leave a record of what happened in RAM
+// that the startup code doesn't touch, then reset the MCU.  At the next boot
+// `take` hands the record over to `init`, which keeps it in EEPROM (see
+// `Nvm::save_crash_record`) so that it can be shown on the e-paper and
+// reported over USB with the diagnostics.
+
+use core::{
+    fmt::{self, Display, Write},
+    mem::MaybeUninit,
+    panic::PanicInfo,
+    ptr,
+};
+
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use int_enum::IntEnum;
+use rtic::Mutex;
+use stm32l0xx_hal::delay::Delay;
+
+use crate::{
+    crc::crc32,
+    display::show_message,
+    errors::Error,
+    spi_bus::{SharedFlash, SpiDevices},
+};
+
+const CRASH_MAGIC: u32 = 0xc4a5_0001;
+
+pub(crate) const CRASH_RECORD_WORDS: usize = core::mem::size_of::<CrashRecord>() / 4;
+
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+#[repr(u32)]
+#[derive(PartialEq, Debug, Clone, Copy, IntEnum)]
+pub(crate) enum CrashKind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+// Only plain words and bytes, so that any bit pattern is a valid (if not
+// necessarily sealed) record
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    // Panic location
+    line: u32,
+    file: [u8; 32],
+    message: [u8; 64],
+    // r0, r1, r2, r3, r12, lr, pc and xpsr as stacked on a HardFault
+    regs: [u32; 8],
+    crc: u32,
+}
+
+impl CrashRecord {
+    fn new(kind: CrashKind) -> Self {
+        Self {
+            magic: CRASH_MAGIC,
+            kind: kind as u32,
+            line: 0,
+            file: [0; 32],
+            message: [0; 64],
+            regs: [0; 8],
+            crc: 0,
+        }
+    }
+
+    pub(crate) fn from_words(words: [u32; CRASH_RECORD_WORDS]) -> Self {
+        unsafe { core::mem::transmute(words) }
+    }
+
+    pub(crate) fn as_words(&self) -> &[u32; CRASH_RECORD_WORDS] {
+        unsafe { &*(self as *const Self as *const [u32; CRASH_RECORD_WORDS]) }
+    }
+
+    fn compute_crc(&self) -> u32 {
+        let words = self.as_words();
+        let mut bytes = [0u8; 4 * (CRASH_RECORD_WORDS - 1)];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        crc32(&bytes)
+    }
+
+    fn seal(&mut self) {
+        self.crc = self.compute_crc();
+    }
+
+    pub(crate) fn is_valid(&self) -> bool {
+        self.magic == CRASH_MAGIC && self.crc == self.compute_crc()
+    }
+
+    pub(crate) fn kind(&self) -> Option<CrashKind> {
+        CrashKind::from_int(self.kind).ok()
+    }
+
+    pub(crate) fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub(crate) fn file(&self) -> &str {
+        text(&self.file)
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        text(&self.message)
+    }
+
+    pub(crate) fn regs(&self) -> &[u32; 8] {
+        &self.regs
+    }
+}
+
+// Up to the first NUL
+// Text cut short when it was saved may end in part of a character
+fn text(buf: &[u8]) -> &str {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len])
+        .unwrap_or_else(|e| core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or("?"))
+}
+
+// The record left by the crash that caused this reset, if any.  Clears it so
+// that it is only reported once.
+pub(crate) fn take() -> Option<CrashRecord> {
+    let record = unsafe { ptr::read_volatile(ptr::addr_of!(CRASH_RECORD) as *const CrashRecord) };
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD) as *mut u32, 0) };
+    record.is_valid().then_some(record)
+}
+
+fn save_and_reset(mut record: CrashRecord) -> ! {
+    record.seal();
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(CRASH_RECORD) as *mut CrashRecord, record) };
+    SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    defmt::error!("{}", defmt::Display2Format(info));
+    let mut record = CrashRecord::new(CrashKind::Panic);
+    if let Some(location) = info.location() {
+        record.line = location.line();
+        // The end of the path is the part that tells files apart
+        let file = location.file().as_bytes();
+        let file = &file[file.len().saturating_sub(record.file.len())..];
+        record.file[..file.len()].copy_from_slice(file);
+    }
+    // Whatever doesn't fit is dropped
+    format_no_std::show(&mut record.message, format_args!("{}", info.message())).ok();
+    save_and_reset(record)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let mut record = CrashRecord::new(CrashKind::HardFault);
+    record.regs = [
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+        frame.lr(),
+        frame.pc(),
+        frame.xpsr(),
+    ];
+    save_and_reset(record)
+}
+
+// Crash screen, shown once after the reset
+pub(crate) async fn show(
+    devices: &mut impl Mutex<T = SpiDevices>,
+    delay: &mut Delay,
+) -> Result<(), Error> {
+    let Some(record) = devices.with_nvm(|nvm| nvm.read_crash_record()) else {
+        return Ok(());
+    };
+    let mut buf = [0u8; 160];
+    let text = match record.kind() {
+        Some(CrashKind::Panic) => {
+            let file = record.file().rsplit('/').next().unwrap_or("");
+            format_no_std::show(
+                &mut buf,
+                format_args!(
+                    "Firmware panic\n{}:{}{}",
+                    file,
+                    record.line(),
+                    Wrapped(record.message())
+                ),
+            )
+            .unwrap_or("Firmware panic")
+        }
+        _ => {
+            let regs = record.regs();
+            format_no_std::show(
+                &mut buf,
+                format_args!(
+                    "HardFault\npc 0x{:08x}\nlr 0x{:08x}\nxpsr 0x{:08x}",
+                    regs[6], regs[5], regs[7]
+                ),
+            )
+            .unwrap_or("HardFault")
+        }
+    };
+    show_message(devices, delay, text).await
+}
+
+// Wraps text to what fits across the screen, starting on a new line
+struct Wrapped<'a>(&'a str);
+
+impl Display for Wrapped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const LINE_LENGTH: usize = 18;
+        for (i, c) in self.0.chars().enumerate() {
+            if i % LINE_LENGTH == 0 {
+                f.write_char('\n')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,30 @@
+// CRC-32 (IEEE 802.3, the one zlib uses).  Computed a bit at a time: slower
+// than a table, but small, and we only run it over a sector or two per card
+// and over the few bytes of an EEPROM record.
+
+pub(crate) const CRC32_INIT: u32 = 0xffff_ffff;
+const CRC32_POLY: u32 = 0xedb8_8320;
+
+// Feed more data into a running CRC started from CRC32_INIT.  The final CRC
+// is the complement of the running one.
+pub(crate) const fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= data[i] as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    crc
+}
+
+pub(crate) const fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
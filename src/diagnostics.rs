@@ -0,0 +1,117 @@
+// Diagnostics report, requested and returned through the second half of the
+// deck config sector, which the config itself doesn't use (see
+// scripts/diagnostics.sh).
+//
+// The host writes DIAGNOSTICS_REQUEST_MAGIC at DIAGNOSTICS_OFFSET in the
+// config sector, keeping the rest of it.  At the next boot the device shows
+// the report on the e-paper and writes it in the same place, from
+// DIAGNOSTICS_OFFSET:
+//
+//   0x0  DIAGNOSTICS_REPORT_MAGIC
+//   0x4  boot count
+//   0x8  number of boot records
+//   0xc  boot records, newest first, 8 bytes each: boot number, reset cause,
+//        wake up reason, charge level (0xff when unknown), padding
+//   0x100 the last crash record as kept in EEPROM (see crash.rs), or 0xff
+//        if there was none
+
+use core::fmt::{self, Display};
+
+use rtic::Mutex;
+use static_assertions as sa;
+use stm32l0xx_hal::delay::Delay;
+
+use crate::{
+    config::config_sector,
+    crash::CRASH_RECORD_WORDS,
+    display::show_message,
+    errors::Error,
+    flash::FLASH_SECTOR_SIZE,
+    nvm::{Nvm, NvmValue, BOOT_COUNT},
+    reset::{boot_history, BootRecord},
+    spi_bus::{SharedFlash, SpiDevices},
+};
+
+const DIAGNOSTICS_REQUEST_MAGIC: u32 = 0xd1a6_0001;
+const DIAGNOSTICS_REPORT_MAGIC: u32 = 0xd1a6_0002;
+const REPORT_HEADER_SIZE: usize = 0xc;
+const BOOT_RECORD_SIZE: usize = 8;
+const CRASH_RECORD_OFFSET: usize = 0x100;
+const DIAGNOSTICS_OFFSET: usize = FLASH_SECTOR_SIZE / 2;
+
+// Boots that fit on the screen under the boot count
+const BOOTS_SHOWN: usize = 6;
+
+pub(crate) fn requested(flash: &mut impl SharedFlash) -> bool {
+    let addr = config_sector(flash) * FLASH_SECTOR_SIZE as u32 + DIAGNOSTICS_OFFSET as u32;
+    let mut buf = [0u8; 4];
+    flash.read(addr, &mut buf).is_ok() && u32::from_le_bytes(buf) == DIAGNOSTICS_REQUEST_MAGIC
+}
+
+pub(crate) async fn report(
+    devices: &mut impl Mutex<T = SpiDevices>,
+    delay: &mut Delay,
+) -> Result<(), Error> {
+    let config_sector = config_sector(devices);
+    devices.with_flash(|flash| {
+        flash.update_sector(config_sector, |buf, nvm| {
+            let buf = &mut buf[DIAGNOSTICS_OFFSET..];
+            buf.fill(0xff);
+            let mut count = 0u32;
+            let records =
+                buf[REPORT_HEADER_SIZE..CRASH_RECORD_OFFSET].chunks_exact_mut(BOOT_RECORD_SIZE);
+            for (record, slot) in boot_history(nvm).zip(records) {
+                record.encode(&mut slot[..BootRecord::SIZE]);
+                count += 1;
+            }
+            if let Some(crash) = nvm.read_crash_record() {
+                let words = buf[CRASH_RECORD_OFFSET..].chunks_exact_mut(4);
+                for (word, bytes) in crash.as_words().iter().zip(words) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            let boots = nvm.get(BOOT_COUNT).unwrap_or(0);
+            buf[0x0..0x4].copy_from_slice(&DIAGNOSTICS_REPORT_MAGIC.to_le_bytes());
+            buf[0x4..0x8].copy_from_slice(&boots.to_le_bytes());
+            buf[0x8..0xc].copy_from_slice(&count.to_le_bytes());
+        })
+    })?;
+
+    let mut buf = [0u8; 160];
+    let buf = &mut buf;
+    let text = devices.with_nvm(move |nvm| {
+        format_no_std::show(
+            buf,
+            format_args!(
+                "Boots: {}{}",
+                nvm.get(BOOT_COUNT).unwrap_or(0),
+                RecentBoots(nvm)
+            ),
+        )
+    });
+    show_message(devices, delay, text.unwrap_or("Diagnostics")).await
+}
+
+// One line for each of the last few boots
+struct RecentBoots<'a>(&'a Nvm);
+
+impl Display for RecentBoots<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for record in boot_history(self.0).take(BOOTS_SHOWN) {
+            let charge: &str = record.charge.map_or("?", |charge| charge.into());
+            write!(
+                f,
+                "\n#{} {} {}",
+                record.boot,
+                <&str>::from(record.cause),
+                charge
+            )?;
+        }
+        Ok(())
+    }
+}
+
+sa::const_assert!(BootRecord::SIZE <= BOOT_RECORD_SIZE);
+sa::const_assert!(
+    DIAGNOSTICS_OFFSET + CRASH_RECORD_OFFSET + 4 * CRASH_RECORD_WORDS <= FLASH_SECTOR_SIZE
+);
@@ -11,14 +11,10 @@ use embedded_graphics::{
     primitives::{Primitive, PrimitiveStyleBuilder, Rectangle},
 };
 
-use rtt_target::rprintln;
-use shared_bus::{NullMutex, SpiProxy};
 use static_assertions as sa;
 
-use stm32l0xx_hal::{
-    delay::Delay,
-    gpio::gpiob::{PB0, PB1, PB2, PB3, PB4, PB5, PB7},
-};
+use rtic_monotonics::stm32::ExtU64;
+use stm32l0xx_hal::delay::Delay;
 
 use u8g2_fonts::{
     fonts,
@@ -26,10 +22,6 @@ use u8g2_fonts::{
     FontRenderer,
 };
 
-use usbd_dfu::{DFUMemError, DFUMemIO};
-
-use crate::flash::SpiFlash;
-
 // For GDE015OC1 use:
 // use epd_waveshare::{epd1in54::*, prelude::*};
 // For GDEH0154D67 use:
@@ -37,148 +29,207 @@ use epd_waveshare::{epd1in54_v2::*, prelude::*};
 
 use crate::{
     config::{FlashConfig, QAType},
-    errors::LightNoteErrors,
+    errors::{Error, LightNoteErrors},
+    spi_bus::{defer_epd_wait, epd_busy, SharedFlash, SpiDevices},
     voltage::{draw_charge_icon, VoltageLevels},
+    Mono,
 };
 
-type Epd<'a> = epd_waveshare::epd1in54_v2::Epd1in54<
-    SpiProxy<
-        'a,
-        NullMutex<
-            stm32l0xx_hal::spi::Spi<
-                stm32l0xx_hal::pac::SPI1,
-                (
-                    PB3<stm32l0xx_hal::gpio::Analog>,
-                    PB4<stm32l0xx_hal::gpio::Analog>,
-                    PB5<stm32l0xx_hal::gpio::Analog>,
-                ),
-            >,
-        >,
-    >,
-    PB2<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-    PB7<stm32l0xx_hal::gpio::Input<stm32l0xx_hal::gpio::Floating>>,
-    PB1<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-    PB0<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-    stm32l0xx_hal::delay::Delay,
->;
-type SpiEpd<'a> = SpiProxy<
-    'a,
-    NullMutex<
-        stm32l0xx_hal::spi::Spi<
-            stm32l0xx_hal::pac::SPI1,
-            (
-                PB3<stm32l0xx_hal::gpio::Analog>,
-                PB4<stm32l0xx_hal::gpio::Analog>,
-                PB5<stm32l0xx_hal::gpio::Analog>,
-            ),
-        >,
-    >,
->;
-
-impl From<DFUMemError> for LightNoteErrors {
-    fn from(_: DFUMemError) -> Self {
-        LightNoteErrors::FailedToReadFromFlash
-    }
-}
-
 #[derive(Debug)]
 pub(crate) enum QAStatus {
     AnswerPending,
     ReadyForNextQuestion,
 }
 
-pub(crate) fn show_q_or_a<'a>(
-    epd: &mut Epd<'a>,
-    spi_epd: &mut SpiEpd<'a>,
+pub(crate) async fn show_q_or_a(
+    devices: &mut impl rtic::Mutex<T = SpiDevices>,
     charge: VoltageLevels,
-    flash: &mut SpiFlash,
     delay: &mut Delay,
     config: &FlashConfig,
     display_addr: u32,
     show_answer: bool,
-) -> Result<QAStatus, LightNoteErrors> {
-
-    rprintln!("show_q_or_a");
+) -> Result<QAStatus, Error> {
+    defmt::info!("show_q_or_a");
     let mut status = QAStatus::ReadyForNextQuestion;
 
-    // Use display graphics from embedded-graphics
-    // let mut display = Display1in54::default();
-
-    // // Display1in54 internal buffer is initialized black.  We want it white.
-    // Rectangle::new(Point::new(0, 0), Size::new(200, 200))
-    //     .into_styled(
-    //         PrimitiveStyleBuilder::new()
-    //             .stroke_width(0)
-    //             .fill_color(Color::White)
-    //             .build(),
-    //     )
-    //     .draw(&mut display)
-    //     .unwrap();
-
-    // flash.check_flash_id()?;
-
-    // const LINE_HEIGHT: u32 = 22;
-    // let font = FontRenderer::new::<fonts::u8g2_font_helvB12_te>()
-    //     .with_ignore_unknown_chars(true)
-    //     .with_line_height(LINE_HEIGHT);
-
-    // const READ_BUFFER_SIZE: usize = 1000;
-    // // READ_BUFFER_SIZE must be a divisor of 5000 so that we read the entire data
-    // // READ_BUFFER_SIZE must be a multiple of 25, which is the data length in bytes
-    // // of a single row
-    // sa::const_assert_eq!(RAW_IMAGE_SIZE % READ_BUFFER_SIZE as u32, 0);
-    // sa::const_assert_eq!(READ_BUFFER_SIZE as u32 % 25, 0);
-    // const RAW_IMAGE_SIZE: u32 = 5000;
-    // const MEM_READS_PER_IMAGE: u32 = RAW_IMAGE_SIZE / (READ_BUFFER_SIZE as u32);
-    // const IMAGE_ROWS_PER_READ: u32 = READ_BUFFER_SIZE as u32 / 25;
-    // let mut addr;
-    // if config.q_type == QAType::RawImage && !show_answer {
-    //     addr = display_addr;
-    //     for i in 0u32..MEM_READS_PER_IMAGE {
-    //         let buf = flash.read(addr, READ_BUFFER_SIZE)?;
-
-    //         let raw_image = ImageRaw::<BinaryColor>::new(&buf[..], 200);
-    //         let image = Image::new(&raw_image, Point::new(0, (i * IMAGE_ROWS_PER_READ) as i32));
-    //         if let Err(_) = image.draw(&mut display.color_converted()) {
-    //             return Err(LightNoteErrors::FailedToRenderImage);
-    //         }
-    //         addr += READ_BUFFER_SIZE as u32;
-    //     }
-    //     if config.a_type == QAType::Text {
-    //         status = QAStatus::AnswerPending;
-    //     }
-    // }
-    // if show_answer && config.a_type == QAType::Text {
-    //      addr = display_addr + RAW_IMAGE_SIZE;
-    //      let buf = flash.read(addr, READ_BUFFER_SIZE)?;
-    //      let mut iter = buf.split(|b| *b == 0u8);
-    //      if let Some(text_buffer) = iter.next() {
-    //         if let Ok(text) = core::str::from_utf8(text_buffer) {
-    //             let c = text.matches("\n").count() as i32;
-    //             let text_origin = Point::new(100, max(0, 100 - LINE_HEIGHT as i32 * (c - 1) / 2));
-    //             if let Err(_) = font.render_aligned(
-    //                 text,
-    //                 text_origin,
-    //                 VerticalPosition::Baseline,
-    //                 HorizontalAlignment::Center,
-    //                 FontColor::Transparent(Color::Black),
-    //                 &mut display,
-    //             ) {
-    //                 return Err(LightNoteErrors::FailedToRenderText);
-    //             }
-    //         }
-    //      }
-    // }
-    // if let Some(charge) = charge_to_show_for(charge) {
-    //     draw_charge_icon(&charge, &mut display);
-    // }
-
-    epd.set_lut(spi_epd, delay, Some(RefreshLut::Full)).unwrap();
-    // epd.update_frame(spi_epd, display.buffer(), delay).unwrap();
-    epd.display_frame(spi_epd, delay).unwrap();
+    let mut display = blank_display();
+
+    devices.with_flash(|flash| flash.check_flash_id())?;
+
+    let font = text_font();
+
+    const READ_BUFFER_SIZE: usize = 1000;
+    // READ_BUFFER_SIZE must be a divisor of 5000 so that we read the entire data
+    // READ_BUFFER_SIZE must be a multiple of 25, which is the data length in bytes
+    // of a single row
+    sa::const_assert_eq!(RAW_IMAGE_SIZE % READ_BUFFER_SIZE as u32, 0);
+    sa::const_assert_eq!(READ_BUFFER_SIZE as u32 % 25, 0);
+    const RAW_IMAGE_SIZE: u32 = 5000;
+    const MEM_READS_PER_IMAGE: u32 = RAW_IMAGE_SIZE / (READ_BUFFER_SIZE as u32);
+    const IMAGE_ROWS_PER_READ: u32 = READ_BUFFER_SIZE as u32 / 25;
+    // Bit rot or a half written card would otherwise show up as noise
+    let intact =
+        devices.with_flash(|flash| flash.check_crc(display_addr, config.page_size as u32))?;
+    if !intact {
+        defmt::error!("Card at 0x{:x} is damaged", display_addr);
+        if let Err(_) = font.render_aligned(
+            "Damaged card",
+            Point::new(100, 100),
+            VerticalPosition::Center,
+            HorizontalAlignment::Center,
+            FontColor::Transparent(Color::Black),
+            &mut display,
+        ) {
+            return Err(LightNoteErrors::FailedToRenderText.into());
+        }
+    }
+
+    let mut addr;
+    if intact && config.q_type == QAType::RawImage && !show_answer {
+        addr = display_addr;
+        for i in 0u32..MEM_READS_PER_IMAGE {
+            let mut buf = [0u8; READ_BUFFER_SIZE];
+            devices.read(addr, &mut buf)?;
+
+            let raw_image = ImageRaw::<BinaryColor>::new(&buf[..], 200);
+            let image = Image::new(&raw_image, Point::new(0, (i * IMAGE_ROWS_PER_READ) as i32));
+            if let Err(_) = image.draw(&mut display.color_converted()) {
+                return Err(LightNoteErrors::FailedToRenderImage.into());
+            }
+            addr += READ_BUFFER_SIZE as u32;
+        }
+        if config.a_type == QAType::Text {
+            status = QAStatus::AnswerPending;
+        }
+    }
+    if intact && show_answer && config.a_type == QAType::Text {
+        addr = display_addr + RAW_IMAGE_SIZE;
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        devices.read(addr, &mut buf)?;
+        let mut iter = buf.split(|b| *b == 0u8);
+        if let Some(text_buffer) = iter.next() {
+            if let Ok(text) = core::str::from_utf8(text_buffer) {
+                let c = text.matches("\n").count() as i32;
+                let text_origin = Point::new(100, max(0, 100 - LINE_HEIGHT as i32 * (c - 1) / 2));
+                if let Err(_) = font.render_aligned(
+                    text,
+                    text_origin,
+                    VerticalPosition::Baseline,
+                    HorizontalAlignment::Center,
+                    FontColor::Transparent(Color::Black),
+                    &mut display,
+                ) {
+                    return Err(LightNoteErrors::FailedToRenderText.into());
+                }
+            }
+        }
+    }
+    if let Some(charge) = charge_to_show_for(charge) {
+        draw_charge_icon(&charge, &mut display);
+    }
+
+    refresh(devices, delay, &display).await?;
     Ok(status)
 }
 
+// Show a few lines of text in the middle of the screen
+pub(crate) async fn show_message(
+    devices: &mut impl rtic::Mutex<T = SpiDevices>,
+    delay: &mut Delay,
+    text: &str,
+) -> Result<(), Error> {
+    let mut display = blank_display();
+    let lines = text.matches("\n").count() as i32 + 1;
+    let text_origin = Point::new(100, 100 - LINE_HEIGHT as i32 * (lines - 1) / 2);
+    if let Err(_) = text_font().render_aligned(
+        text,
+        text_origin,
+        VerticalPosition::Center,
+        HorizontalAlignment::Center,
+        FontColor::Transparent(Color::Black),
+        &mut display,
+    ) {
+        return Err(LightNoteErrors::FailedToRenderText.into());
+    }
+    refresh(devices, delay, &display).await
+}
+
+// The error code and what to do about it.  The panel keeps showing it after
+// we run out of power.
+pub(crate) async fn show_error(
+    devices: &mut impl rtic::Mutex<T = SpiDevices>,
+    delay: &mut Delay,
+    error: LightNoteErrors,
+) -> Result<(), Error> {
+    let mut buf = [0u8; 100];
+    let text = format_no_std::show(
+        &mut buf,
+        format_args!(
+            "Error {}\n{}\n\n{}",
+            error as u8,
+            error.name(),
+            error.remedy()
+        ),
+    )
+    .unwrap_or(error.name());
+    show_message(devices, delay, text).await
+}
+
+const LINE_HEIGHT: u32 = 22;
+
+// How often to check whether the panel is done refreshing
+const REFRESH_POLL_MS: u64 = 50;
+
+fn text_font() -> FontRenderer {
+    FontRenderer::new::<fonts::u8g2_font_helvB12_te>()
+        .with_ignore_unknown_chars(true)
+        .with_line_height(LINE_HEIGHT)
+}
+
+fn blank_display() -> Display1in54 {
+    // Use display graphics from embedded-graphics
+    let mut display = Display1in54::default();
+
+    // Display1in54 internal buffer is initialized black.  We want it white.
+    Rectangle::new(Point::new(0, 0), Size::new(200, 200))
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_width(0)
+                .fill_color(Color::White)
+                .build(),
+        )
+        .draw(&mut display)
+        .unwrap();
+    display
+}
+
+async fn refresh(
+    devices: &mut impl rtic::Mutex<T = SpiDevices>,
+    delay: &mut Delay,
+    display: &Display1in54,
+) -> Result<(), Error> {
+    devices
+        .lock(|devices| {
+            let SpiDevices { epd, spi_epd, .. } = devices;
+            epd.set_lut(spi_epd, delay, Some(RefreshLut::Full))?;
+            epd.update_frame(spi_epd, display.buffer(), delay)
+        })
+        .map_err(|_| Error::Display)?;
+    // Only start the refresh under the lock, and wait for the panel outside
+    // of it so that USB keeps being served
+    defer_epd_wait(true);
+    let started = devices.lock(|devices| {
+        let SpiDevices { epd, spi_epd, .. } = devices;
+        epd.display_frame(spi_epd, delay)
+    });
+    defer_epd_wait(false);
+    started.map_err(|_| Error::Display)?;
+    while epd_busy() {
+        Mono::delay(REFRESH_POLL_MS.millis()).await;
+    }
+    Ok(())
+}
+
 pub(crate) fn charge_to_show_for(charge: VoltageLevels) -> Option<VoltageLevels> {
     // Charge indicator distracts, only show it if voltage is low
     if charge <= VoltageLevels::Low {
@@ -0,0 +1,190 @@
+// SPI1 with DMA behind the blocking embedded-hal traits.
+//
+// The w25q and epd-waveshare drivers only know blocking `Transfer`/`Write`,
+// so rather than teaching them about DMA we give them a bus that moves long
+// buffers with DMA1 (channel 2 for RX, channel 3 for TX) and sleeps with WFE
+// until the transfer completes.  Short transfers (commands, addresses) go
+// byte by byte, where setting up the DMA would cost more than it saves.
+//
+// Everything on SPI1 is serialized by the `spi_devices` lock, so a transfer
+// never starts while another one is in flight.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+
+use cortex_m::{
+    asm,
+    peripheral::{NVIC, SCB},
+    prelude::{
+        _embedded_hal_blocking_spi_Transfer as Transfer, _embedded_hal_blocking_spi_Write as Write,
+    },
+};
+use stm32l0xx_hal::{
+    gpio::{
+        gpiob::{PB3, PB4, PB5},
+        Analog,
+    },
+    pac::{Interrupt, DMA1, RCC, SPI1},
+    spi::Spi,
+};
+
+type Pins = (PB3<Analog>, PB4<Analog>, PB5<Analog>);
+
+// Below this many bytes polling is faster than setting up a DMA transfer
+const DMA_THRESHOLD: usize = 16;
+// DMA request mapping for SPI1 on channels 2 and 3 (RM0367, table 51)
+const CSELR_SPI1: u8 = 0b0001;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    Overrun,
+    Dma,
+}
+
+pub(crate) struct DmaSpi {
+    spi: SPI1,
+    dma: DMA1,
+    _pins: Pins,
+}
+
+impl DmaSpi {
+    // Takes over a SPI already configured by the HAL (mode, clock)
+    pub(crate) fn new(spi: Spi<SPI1, Pins>, dma: DMA1, scb: &mut SCB) -> Self {
+        let (spi, pins) = spi.free();
+        // The HAL's DMA handle would want to own all channels, just turn on
+        // the clock
+        unsafe { (*RCC::ptr()).ahbenr.modify(|_, w| w.dmaen().set_bit()) };
+        dma.cselr
+            .modify(|_, w| unsafe { w.c2s().bits(CSELR_SPI1).c3s().bits(CSELR_SPI1) });
+        let dr = &spi.dr as *const _ as u32;
+        dma.cpar2.write(|w| unsafe { w.pa().bits(dr) });
+        dma.cpar3.write(|w| unsafe { w.pa().bits(dr) });
+        // Let the (masked) DMA interrupt wake us up from WFE
+        scb.set_sevonpend();
+        Self {
+            spi,
+            dma,
+            _pins: pins,
+        }
+    }
+
+    fn exchange_polled(&mut self, tx: &[u8], rx: Option<&mut [u8]>) -> Result<(), Error> {
+        let mut rx = rx;
+        for (i, byte) in tx.iter().enumerate() {
+            while self.spi.sr.read().txe().bit_is_clear() {}
+            self.spi.dr.write(|w| unsafe { w.dr().bits(*byte as u16) });
+            while self.spi.sr.read().rxne().bit_is_clear() {}
+            let received = self.spi.dr.read().dr().bits() as u8;
+            if let Some(rx) = rx.as_deref_mut() {
+                rx[i] = received;
+            }
+        }
+        Ok(())
+    }
+
+    // Clock `len` bytes out of `tx` and into `rx`.  Pointers that don't
+    // increment (a single dummy byte) are used for the direction we don't
+    // care about.
+    fn exchange_dma(
+        &mut self,
+        tx: *const u8,
+        tx_increment: bool,
+        rx: *mut u8,
+        rx_increment: bool,
+        len: usize,
+    ) -> Result<(), Error> {
+        let dma = &self.dma;
+        dma.cmar2.write(|w| unsafe { w.ma().bits(rx as u32) });
+        dma.cndtr2.write(|w| unsafe { w.ndt().bits(len as u16) });
+        dma.cmar3.write(|w| unsafe { w.ma().bits(tx as u32) });
+        dma.cndtr3.write(|w| unsafe { w.ndt().bits(len as u16) });
+        dma.ifcr.write(|w| w.cgif2().set_bit().cgif3().set_bit());
+        compiler_fence(Ordering::SeqCst);
+
+        // RX first so that no byte is missed, then TX starts the clock
+        dma.ccr2.write(|w| {
+            w.minc()
+                .bit(rx_increment)
+                .tcie()
+                .set_bit()
+                .teie()
+                .set_bit()
+                .en()
+                .set_bit()
+        });
+        self.spi.cr2.modify(|_, w| w.rxdmaen().set_bit());
+        // TX only needs to wake us up on an error, completion is seen on RX
+        dma.ccr3.write(|w| {
+            w.dir()
+                .set_bit()
+                .minc()
+                .bit(tx_increment)
+                .teie()
+                .set_bit()
+                .en()
+                .set_bit()
+        });
+        self.spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+
+        let result = loop {
+            let isr = dma.isr.read();
+            if isr.teif2().bit_is_set() || isr.teif3().bit_is_set() {
+                break Err(Error::Dma);
+            }
+            if isr.tcif2().bit_is_set() {
+                break Ok(());
+            }
+            asm::wfe();
+        };
+
+        dma.ccr2.modify(|_, w| w.en().clear_bit());
+        dma.ccr3.modify(|_, w| w.en().clear_bit());
+        self.spi
+            .cr2
+            .modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+        dma.ifcr.write(|w| w.cgif2().set_bit().cgif3().set_bit());
+        NVIC::unpend(Interrupt::DMA1_CHANNEL2_3);
+        compiler_fence(Ordering::SeqCst);
+
+        if self.spi.sr.read().ovr().bit_is_set() {
+            // Cleared by reading DR then SR
+            let _ = self.spi.dr.read();
+            let _ = self.spi.sr.read();
+            return Err(Error::Overrun);
+        }
+        result
+    }
+}
+
+impl Transfer<u8> for DmaSpi {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        if words.len() < DMA_THRESHOLD {
+            let mut tx = [0u8; DMA_THRESHOLD];
+            tx[..words.len()].copy_from_slice(words);
+            self.exchange_polled(&tx[..words.len()], Some(words))?;
+        } else {
+            // TX reads each byte before RX overwrites it
+            for chunk in words.chunks_mut(u16::MAX as usize) {
+                let ptr = chunk.as_mut_ptr();
+                self.exchange_dma(ptr, true, ptr, true, chunk.len())?;
+            }
+        }
+        Ok(words)
+    }
+}
+
+impl Write<u8> for DmaSpi {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        if words.len() < DMA_THRESHOLD {
+            return self.exchange_polled(words, None);
+        }
+        let mut sink = 0u8;
+        for chunk in words.chunks(u16::MAX as usize) {
+            self.exchange_dma(chunk.as_ptr(), true, &mut sink, false, chunk.len())?;
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
-use cortex_m::prelude::_embedded_hal_blocking_delay_DelayMs;
-use stm32l0xx_hal::{
-    delay::Delay,
-    gpio::{Output, Pin, PushPull},
-    prelude::OutputPin,
-};
+use rtic::Mutex;
+use stm32l0xx_hal::delay::Delay;
+use usbd_scsi::BlockDeviceError;
 
-// use crate::config::FlashConfigError;
+use crate::{
+    config::FlashConfigError,
+    display::show_error,
+    led::{self, LedSender},
+    nvm,
+    spi_bus::SpiDevices,
+};
 
 #[derive(Clone, Copy)]
 pub(super) enum LightNoteErrors {
@@ -13,64 +16,162 @@ pub(super) enum LightNoteErrors {
     InvalidFlashConfigMagicId = 32,
     InvalidQAType = 33,
     FailedToReadFlashID = 37,
-    // FailedToInitializeFlash = 38,
+    FailedToInitializeFlash = 38,
+    UnknownFlash = 39,
+    FlashTooLarge = 40,
     AwakenedByUnexpectedEvent = 41,
     FailedToRenderText = 44,
     FailedToRenderImage = 45,
+    FailedToUpdateDisplay = 46,
     // FailedToReadOrientation = 66,
     FailedToReadFromFlash = 73,
+    FailedToWriteNvm = 74,
+    FailedToReadTemperature = 75,
+    FailedToReadCharge = 76,
 }
 
-// impl From<FlashConfigError> for LightNoteErrors {
-//     fn from(fc_error: FlashConfigError) -> Self {
-//         match fc_error {
-//             FlashConfigError::InvalidFlashConfigMagicId => {
-//                 LightNoteErrors::InvalidFlashConfigMagicId
-//             }
-//             FlashConfigError::InvalidQAType => LightNoteErrors::InvalidQAType,
-//             FlashConfigError::FailedToReadFlash => LightNoteErrors::FailedToReadFromFlash,
-//         }
-//     }
-// }
+impl LightNoteErrors {
+    // Short enough to fit across the screen
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            LightNoteErrors::FailedToVerifyAccelConfig => "Accelerometer",
+            LightNoteErrors::InvalidFlashConfigMagicId => "No deck found",
+            LightNoteErrors::InvalidQAType => "Bad deck config",
+            LightNoteErrors::FailedToReadFlashID => "Flash not found",
+            LightNoteErrors::FailedToInitializeFlash => "Flash init failed",
+            LightNoteErrors::UnknownFlash => "Unknown flash",
+            LightNoteErrors::FlashTooLarge => "Flash too large",
+            LightNoteErrors::AwakenedByUnexpectedEvent => "Unexpected wake",
+            LightNoteErrors::FailedToRenderText => "Bad card text",
+            LightNoteErrors::FailedToRenderImage => "Bad card image",
+            LightNoteErrors::FailedToUpdateDisplay => "Display failed",
+            LightNoteErrors::FailedToReadFromFlash => "Flash read failed",
+            LightNoteErrors::FailedToWriteNvm => "EEPROM failed",
+            LightNoteErrors::FailedToReadTemperature => "Sensor failed",
+            LightNoteErrors::FailedToReadCharge => "ADC failed",
+        }
+    }
 
-#[allow(dead_code)]
-pub(super) fn raise(
-    error: LightNoteErrors,
-    led1: &mut Pin<Output<PushPull>>,
-    led10: &mut Pin<Output<PushPull>>,
-    delay: &mut Delay,
-) {
-    let tens = (error as u8) / 10;
-    let fifties = tens / 5;
-    let tens = tens % 5;
-    for _ in 0..fifties {
-        led10.set_high().ok();
-        delay.delay_ms(2_000u32);
-        led10.set_low().ok();
-        delay.delay_ms(500u32);
+    // What the user can do about it
+    pub(super) fn remedy(self) -> &'static str {
+        match self {
+            LightNoteErrors::InvalidFlashConfigMagicId => "Copy a deck\nover USB",
+            LightNoteErrors::InvalidQAType
+            | LightNoteErrors::FailedToRenderText
+            | LightNoteErrors::FailedToRenderImage => "Recreate the deck",
+            LightNoteErrors::UnknownFlash | LightNoteErrors::FlashTooLarge => "Update firmware",
+            LightNoteErrors::FailedToReadFromFlash => "Run the self-test",
+            LightNoteErrors::FailedToWriteNvm => "Let it charge,\nthen power cycle",
+            LightNoteErrors::FailedToVerifyAccelConfig
+            | LightNoteErrors::FailedToReadFlashID
+            | LightNoteErrors::FailedToInitializeFlash
+            | LightNoteErrors::AwakenedByUnexpectedEvent
+            | LightNoteErrors::FailedToUpdateDisplay
+            | LightNoteErrors::FailedToReadTemperature
+            | LightNoteErrors::FailedToReadCharge => "Power cycle",
+        }
+    }
+}
+
+impl From<FlashConfigError> for LightNoteErrors {
+    fn from(fc_error: FlashConfigError) -> Self {
+        match fc_error {
+            FlashConfigError::InvalidFlashConfigMagicId => {
+                LightNoteErrors::InvalidFlashConfigMagicId
+            }
+            FlashConfigError::InvalidQAType => LightNoteErrors::InvalidQAType,
+            FlashConfigError::FailedToReadFlash => LightNoteErrors::FailedToReadFromFlash,
+        }
     }
+}
 
-    for _ in 0..tens {
-        led10.set_high().ok();
-        delay.delay_ms(500u32);
-        led10.set_low().ok();
-        delay.delay_ms(500u32);
+impl From<nvm::Error> for LightNoteErrors {
+    fn from(_: nvm::Error) -> Self {
+        LightNoteErrors::FailedToWriteNvm
     }
-    let ones = (error as u8) % 10;
-    let fives = ones / 5;
-    let ones = ones % 5;
-    for _ in 0..fives {
-        led1.set_high().ok();
-        delay.delay_ms(2_000u32);
-        led1.set_low().ok();
-        delay.delay_ms(500u32);
+}
+
+// What the app tasks deal in.  Keeps the subsystem's own error for the log,
+// while `code` gives the number shown to the user.
+pub(super) enum Error {
+    LightNote(LightNoteErrors),
+    Nvm(nvm::Error),
+    FlashConfig(FlashConfigError),
+    BlockDevice(BlockDeviceError),
+    // EPD driver, I2C sensors and ADC errors carry nothing worth keeping
+    Display,
+    Sensor,
+    Adc,
+}
+
+impl Error {
+    pub(super) fn code(&self) -> LightNoteErrors {
+        match self {
+            Error::LightNote(e) => *e,
+            Error::Nvm(e) => (*e).into(),
+            Error::FlashConfig(e) => (*e).into(),
+            Error::BlockDevice(_) => LightNoteErrors::FailedToReadFromFlash,
+            Error::Display => LightNoteErrors::FailedToUpdateDisplay,
+            Error::Sensor => LightNoteErrors::FailedToReadTemperature,
+            Error::Adc => LightNoteErrors::FailedToReadCharge,
+        }
     }
+}
 
-    for _ in 0..ones {
-        led1.set_high().ok();
-        delay.delay_ms(500u32);
-        led1.set_low().ok();
-        delay.delay_ms(500u32);
+impl defmt::Format for Error {
+    fn format(&self, f: defmt::Formatter) {
+        let code = self.code();
+        defmt::write!(f, "{} {}", code as u8, code.name());
+        match self {
+            Error::Nvm(e) => defmt::write!(f, " ({})", defmt::Debug2Format(e)),
+            Error::FlashConfig(e) => defmt::write!(f, " ({})", defmt::Debug2Format(e)),
+            Error::BlockDevice(e) => {
+                let write = matches!(
+                    e,
+                    BlockDeviceError::WriteError | BlockDeviceError::EraseError
+                );
+                defmt::write!(f, " (flash {})", if write { "write" } else { "read" })
+            }
+            Error::LightNote(_) | Error::Display | Error::Sensor | Error::Adc => {}
+        }
+    }
+}
+
+impl From<LightNoteErrors> for Error {
+    fn from(e: LightNoteErrors) -> Self {
+        Error::LightNote(e)
+    }
+}
+
+impl From<nvm::Error> for Error {
+    fn from(e: nvm::Error) -> Self {
+        Error::Nvm(e)
+    }
+}
+
+impl From<FlashConfigError> for Error {
+    fn from(e: FlashConfigError) -> Self {
+        Error::FlashConfig(e)
+    }
+}
+
+impl From<BlockDeviceError> for Error {
+    fn from(e: BlockDeviceError) -> Self {
+        Error::BlockDevice(e)
+    }
+}
+
+// Show the error on the e-paper.  Only if the display itself is what failed,
+// blink the code on the LED instead.
+pub(super) async fn raise(
+    error: Error,
+    devices: &mut impl Mutex<T = SpiDevices>,
+    led: &mut LedSender,
+    delay: &mut Delay,
+) {
+    defmt::error!("Error {}", error);
+    let code = error.code();
+    if show_error(devices, delay, code).await.is_err() {
+        led::raise(led, code);
     }
-    delay.delay_ms(5_000u32);
 }
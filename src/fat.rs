@@ -0,0 +1,100 @@
+// Just enough FAT32 to tell which parts of the flash hold no file data.
+//
+// scripts/format_lightnote.sh always formats the drive as FAT32 with no
+// partition table, so the boot sector is at address 0.
+
+use usbd_scsi::BlockDeviceError;
+
+use crate::flash::{SpiFlash, FLASH_SECTOR_SIZE};
+
+pub(crate) const BOOT_SECTOR_SIZE: usize = 512;
+
+const FAT32_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FIRST_CLUSTER: u32 = 2;
+
+pub(crate) struct Fat32 {
+    // Byte addresses of the first FAT and of cluster 2
+    fat_start: u32,
+    data_start: u32,
+    cluster_size: u32,
+    num_clusters: u32,
+}
+
+fn le16(buf: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]]) as u32
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+impl Fat32 {
+    // None unless the boot sector describes a sane FAT32 volume
+    pub(crate) fn parse(boot: &[u8; BOOT_SECTOR_SIZE]) -> Option<Self> {
+        if boot[510] != 0x55 || boot[511] != 0xaa {
+            return None;
+        }
+        let bytes_per_sector = le16(boot, 11);
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = le16(boot, 14);
+        let num_fats = boot[16] as u32;
+        let root_entries = le16(boot, 17);
+        let fat_size16 = le16(boot, 22);
+        let total_sectors = le32(boot, 32);
+        let fat_size = le32(boot, 36);
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || root_entries != 0
+            || fat_size16 != 0
+            || fat_size == 0
+        {
+            return None;
+        }
+        let data_sector = reserved_sectors + num_fats * fat_size;
+        let num_clusters = total_sectors.checked_sub(data_sector)? / sectors_per_cluster;
+        // The FAT must have room for every cluster
+        if (num_clusters + FIRST_CLUSTER) * 4 > fat_size * bytes_per_sector {
+            return None;
+        }
+        Some(Self {
+            fat_start: reserved_sectors * bytes_per_sector,
+            data_start: data_sector * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            num_clusters,
+        })
+    }
+
+    // True if every cluster overlapping the logical flash sector is free.
+    // Sectors holding anything else (boot sector, FATs, the tail past the last
+    // cluster) are never free.
+    pub(crate) fn is_sector_free(
+        &self,
+        flash: &mut SpiFlash,
+        sector: u32,
+    ) -> Result<bool, BlockDeviceError> {
+        let start = sector * FLASH_SECTOR_SIZE as u32;
+        let end = start + FLASH_SECTOR_SIZE as u32 - 1;
+        if start < self.data_start {
+            return Ok(false);
+        }
+        let first = FIRST_CLUSTER + (start - self.data_start) / self.cluster_size;
+        let last = FIRST_CLUSTER + (end - self.data_start) / self.cluster_size;
+        if last >= FIRST_CLUSTER + self.num_clusters {
+            return Ok(false);
+        }
+        let mut entry = [0u8; 4];
+        for cluster in first..=last {
+            flash.read(self.fat_start + cluster * 4, &mut entry)?;
+            if u32::from_le_bytes(entry) & FAT32_ENTRY_MASK != 0 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
@@ -1,105 +1,878 @@
-use core::cell::RefCell;
-use cortex_m::prelude::_embedded_hal_blocking_delay_DelayMs;
-use shared_bus::{NullMutex, SpiProxy};
+use core::{
+    cell::RefCell,
+    cmp::{max, min},
+    ops::Range,
+};
+use cortex_m::prelude::{
+    _embedded_hal_blocking_delay_DelayMs, _embedded_hal_blocking_spi_Transfer,
+};
 use stm32l0xx_hal::{
     delay::Delay,
-    gpio::{
-        gpiob::{PB3, PB4, PB5, PB6},
-        Output, PushPull,
-    },
+    gpio::{gpiob::PB6, Output, PushPull},
     prelude::OutputPin,
 };
 use w25q::series25::Flash;
 
 use usbd_scsi::{BlockDevice, BlockDeviceError};
 
-use crate::{errors::LightNoteErrors, nvm::{self, Nvm}};
+#[cfg(any(feature = "sector-crc", feature = "journaled-writes"))]
+use crate::crc::crc32;
+#[cfg(feature = "sector-crc")]
+use crate::crc::{crc32_update, CRC32_INIT};
+#[cfg(feature = "wear-leveling")]
+use crate::ftl::{self, Ftl};
+use crate::{
+    errors::LightNoteErrors,
+    geometry::FlashGeometry,
+    nvm::{self, Nvm, MAX_FLASH_SECTORS},
+    spi_bus::{ChipSelect, FlashCs, FlashCsCell, SpiProxy},
+};
 
 impl From<nvm::Error> for BlockDeviceError {
     fn from(value: nvm::Error) -> Self {
         match value {
             nvm::Error::InvalidAddress => BlockDeviceError::InvalidAddress,
+            // A map or journal update that didn't stick leaves the write
+            // unaccounted for
+            nvm::Error::Programming => BlockDeviceError::WriteError,
+            nvm::Error::OutOfRange | nvm::Error::Alignment => BlockDeviceError::HardwareError,
+        }
+    }
+}
+
+pub(crate) const FLASH_SECTOR_SIZE: usize = 4096;
+
+// Some hosts and tools don't cope with 4 KiB sector USB sticks.  With
+// `emulate-512b-blocks` we report 512 byte LBAs and read-modify-write the flash
+// sector that contains them.
+#[cfg(not(feature = "emulate-512b-blocks"))]
+const LOGICAL_BLOCK_SIZE: usize = FLASH_SECTOR_SIZE;
+#[cfg(feature = "emulate-512b-blocks")]
+const LOGICAL_BLOCK_SIZE: usize = 512;
+const BLOCKS_PER_SECTOR: u32 = (FLASH_SECTOR_SIZE / LOGICAL_BLOCK_SIZE) as u32;
+
+// Sectors at the end of the flash that the host never sees: the journal
+// sectors right after the visible ones, then the CRC table, then the wear
+// leveling spares.  Where that end is depends on the size of the flash, see
+// `visible_sectors`, and so does the size of the CRC table, see
+// `crc_table_sectors`.
+#[cfg(feature = "journaled-writes")]
+const JOURNAL_SECTORS: u32 = JOURNAL_SLOTS + 1;
+#[cfg(not(feature = "journaled-writes"))]
+const JOURNAL_SECTORS: u32 = 0;
+
+// The CRC table has CRC_SLOTS slots per sector.  Rewriting a sector programs
+// its next free slot, the last programmed one is current.  Only when all
+// slots are used does the table sector need an erase.  A write takes two
+// slots: CRC_UNKNOWN before the sector is touched, the real CRC once it is
+// written, so a reset in between leaves no stale CRC behind.
+#[cfg(feature = "sector-crc")]
+const CRC_SLOTS: usize = 8;
+#[cfg(feature = "sector-crc")]
+const CRC_UNKNOWN: u32 = 0;
+#[cfg(feature = "sector-crc")]
+const SECTORS_PER_CRC_TABLE_SECTOR: u32 = (FLASH_SECTOR_SIZE / (CRC_SLOTS * 4)) as u32;
+#[cfg(feature = "sector-crc")]
+const ERASED_SECTOR_CRC: u32 = crc32(&[0xff; FLASH_SECTOR_SIZE]);
+// The journal stages data in JOURNAL_SLOTS sectors taken in turn, so that no
+// single sector takes an erase per journaled write.  The sector after them
+// holds a log of JOURNAL_RECORD_SIZE records, appended one per write and only
+// erased once full: sequence number, target sector, CRC of the staged data,
+// CRC of those three words, then a word cleared once the target is written.
+// The data of sequence number n is in slot n % JOURNAL_SLOTS.
+#[cfg(feature = "journaled-writes")]
+const JOURNAL_SLOTS: u32 = 8;
+#[cfg(feature = "journaled-writes")]
+const JOURNAL_RECORD_SIZE: usize = 32;
+#[cfg(feature = "journaled-writes")]
+const JOURNAL_RECORDS: u32 = (FLASH_SECTOR_SIZE / JOURNAL_RECORD_SIZE) as u32;
+#[cfg(feature = "journaled-writes")]
+const JOURNAL_DONE_OFFSET: u32 = 16;
+#[cfg(feature = "wear-leveling")]
+const FTL_SPARE_SECTORS: u32 = ftl::SPARE_SECTORS;
+#[cfg(not(feature = "wear-leveling"))]
+const FTL_SPARE_SECTORS: u32 = 0;
+
+const CMD_POWER_DOWN: u8 = 0xb9;
+const CMD_RELEASE_POWER_DOWN: u8 = 0xab;
+// tRES1 is 3 us, we run at 16 MHz
+const RELEASE_POWER_DOWN_CYCLES: u32 = 64;
+
+// Single sector cache.  With `write-back-cache`, FAT rewrites of the same
+// sector (FAT table, directory entries) are held in RAM until the host moves
+// on, saving an erase + program per rewrite.  Otherwise every write goes
+// straight through and the cache only saves re-reading the sector.
+struct SectorCache {
+    buf: &'static mut [u8],
+    sector: Option<u32>,
+    dirty: bool,
+    // Written to since the last idle check
+    touched: bool,
+}
+
+impl SectorCache {
+    fn invalidate(&mut self) {
+        self.sector = None;
+        self.dirty = false;
+    }
+
+    // Overlay cached data on a buffer just read from flash at `addr`
+    fn patch(&self, addr: u32, buf: &mut [u8]) {
+        if let Some(sector) = self.sector {
+            let start = sector * FLASH_SECTOR_SIZE as u32;
+            let end = start + FLASH_SECTOR_SIZE as u32;
+            let buf_end = addr + buf.len() as u32;
+            if addr < end && buf_end > start {
+                let from = max(addr, start);
+                let to = min(buf_end, end);
+                buf[(from - addr) as usize..(to - addr) as usize]
+                    .copy_from_slice(&self.buf[(from - start) as usize..(to - start) as usize]);
+            }
         }
     }
 }
 
-const FLASH_SECTOR_SIZE: usize = 4096;
+// One bit per flash sector
+struct SectorSet([u32; (MAX_FLASH_SECTORS as usize + 31) / 32]);
+
+impl SectorSet {
+    const fn new() -> Self {
+        Self([0; (MAX_FLASH_SECTORS as usize + 31) / 32])
+    }
+
+    fn contains(&self, sector: u32) -> bool {
+        self.0[sector as usize / 32] & (1 << (sector % 32)) != 0
+    }
+
+    fn set(&mut self, sector: u32) {
+        self.0[sector as usize / 32] |= 1 << (sector % 32);
+    }
+
+    fn remove(&mut self, sector: u32) {
+        self.0[sector as usize / 32] &= !(1 << (sector % 32));
+    }
+
+    fn first_in(&self, sectors: Range<u32>) -> Option<u32> {
+        sectors.into_iter().find(|&sector| self.contains(sector))
+    }
+}
 
-impl BlockDevice for SpiFlash<'_> {
-    const BLOCK_BYTES: usize = FLASH_SECTOR_SIZE;
+impl BlockDevice for SpiFlash {
+    const BLOCK_BYTES: usize = LOGICAL_BLOCK_SIZE;
 
     fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
         // defmt::info!("read_block {}", lba);
-        self.flash
-            .borrow_mut()
-            .read(lba * Self::BLOCK_BYTES as u32, block)
-            .map_err(|_| BlockDeviceError::HardwareError)?;
-        Ok(())
+        self.host_active = true;
+        self.read(lba * Self::BLOCK_BYTES as u32, block)
     }
 
     fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
-        if self.nvm.read_sector_is_erased(lba)? {
-            self.write_block_fast(lba, block)?;
-        } else {
-            self.write_block_slow(lba, block)?;
+        if block.len() != LOGICAL_BLOCK_SIZE {
+            return Err(BlockDeviceError::WriteError);
         }
-        self.nvm.save_sector_is_erased(lba, false).map_err(|e| e.into())
+        let sector = lba / BLOCKS_PER_SECTOR;
+        let offset = (lba % BLOCKS_PER_SECTOR) as usize * LOGICAL_BLOCK_SIZE;
+        self.host_active = true;
+        self.written.set(sector);
+
+        if self.cache.sector != Some(sector) {
+            self.flush()?;
+            if LOGICAL_BLOCK_SIZE < FLASH_SECTOR_SIZE {
+                // Only part of the sector is being replaced, start from what
+                // is in flash
+                let addr = self.physical_addr(sector * FLASH_SECTOR_SIZE as u32);
+                let buf = core::mem::take(&mut self.cache.buf);
+                let result = self.read_physical(addr, buf);
+                self.cache.buf = buf;
+                result?;
+            }
+            self.cache.sector = Some(sector);
+        }
+        self.cache.buf[offset..offset + LOGICAL_BLOCK_SIZE].copy_from_slice(block);
+        self.cache.dirty = true;
+        self.cache.touched = true;
+        // usbd_scsi doesn't pass SYNCHRONIZE CACHE or an eject down to us, so
+        // nothing tells us when the host expects its data to be in flash
+        #[cfg(not(feature = "write-back-cache"))]
+        {
+            if LOGICAL_BLOCK_SIZE < FLASH_SECTOR_SIZE
+                && self.write_in_place(sector, offset, block)?
+            {
+                self.cache.dirty = false;
+                self.programmed = true;
+                return Ok(());
+            }
+            self.flush()?;
+        }
+        Ok(())
     }
 
+    // A chip erase takes tens of seconds, much longer than hosts wait for a
+    // command, so this only notes which sectors need erasing, see
+    // `erase_blocks`
     fn erase_device(&mut self) -> Result<(), BlockDeviceError> {
-        self.flash
-            .get_mut()
-            .erase_all()
-            .map_err(|_| BlockDeviceError::EraseError)?;
-        self.nvm.save_all_sectors_erased().map_err(|e| e.into())
+        self.erase_blocks(0, self.max_lba() + 1)
     }
 
     fn max_lba(&self) -> u32 {
-        16 * 1024 * 1024 / Self::BLOCK_BYTES as u32 - 1
+        self.visible_sectors() * BLOCKS_PER_SECTOR - 1
     }
 }
 
-impl<'a> SpiFlash<'a> {
+impl SpiFlash {
     pub(crate) fn new(
-        spi_flash: SpiFlashMainType<'a>,
+        mut spi_flash: SpiProxy,
         mut cs_flash: PB6<Output<PushPull>>,
+        cs_cell: &'static mut Option<FlashCsCell>,
         mut nvm: Nvm,
         delay: &mut Delay,
-    ) -> Self {
+        cache_buf: &'static mut [u8; FLASH_SECTOR_SIZE],
+    ) -> Result<Self, LightNoteErrors> {
         // Wiggle chip select seems to avoid Flash::init failures that occur in
         // transient power losses in the middle of a memory read
         cs_flash.set_high().unwrap();
         delay.delay_ms(100u32);
         cs_flash.set_low().unwrap();
 
-        let flash = Flash::init(spi_flash, cs_flash);
-        // if flash.is_err() {
-        //     errors::raise(
-        //         LightNoteErrors::FailedToInitializeFlash,
-        //         &mut led_grn,
-        //         &mut led_ylw,
-        //         &mut delay,
-        //     );
-        //     SCB::sys_reset();
-        // }
-        let flash = flash.unwrap();
-        SpiFlash {
+        // The flash stays powered down across an MCU reset
+        let mut cs = FlashCs::new(cs_cell.insert(RefCell::new(ChipSelect::new(cs_flash))));
+        flash_command(
+            &mut spi_flash,
+            &mut cs,
+            &mut [CMD_RELEASE_POWER_DOWN],
+            &mut [],
+        )
+        .map_err(|_| LightNoteErrors::FailedToReadFlashID)?;
+        cortex_m::asm::delay(RELEASE_POWER_DOWN_CYCLES);
+
+        let geometry = FlashGeometry::probe(&mut spi_flash, &mut cs)?;
+
+        let flash =
+            Flash::init(spi_flash, cs).map_err(|_| LightNoteErrors::FailedToInitializeFlash)?;
+        let mut pending_erase = SectorSet::new();
+        nvm.read_wipe_map(&mut pending_erase.0)
+            .map_err(|_| LightNoteErrors::FailedToInitializeFlash)?;
+        if pending_erase.first_in(0..geometry.num_sectors).is_some() {
+            defmt::info!("Resuming device erase");
+        }
+        let mut flash = SpiFlash {
             flash: RefCell::new(flash),
+            #[cfg(feature = "wear-leveling")]
+            ftl: Ftl::new(&nvm, geometry.num_sectors),
+            geometry,
+            spi: spi_flash,
+            cs,
+            powered_down: false,
+            accessed: false,
             nvm,
+            host_active: false,
+            programmed: false,
+            written: SectorSet::new(),
+            pending_erase,
+            erase_cursor: 0,
+            #[cfg(feature = "journaled-writes")]
+            journal_seq: 0,
+            #[cfg(feature = "journaled-writes")]
+            journal_records: 0,
+            cache: SectorCache {
+                buf: cache_buf,
+                sector: None,
+                dirty: false,
+                touched: false,
+            },
+        };
+
+        #[cfg(feature = "journaled-writes")]
+        if flash.recover_journal().is_err() {
+            defmt::error!("Failed to replay journaled write");
+        }
+        Ok(flash)
+    }
+
+    pub(crate) fn num_sectors(&self) -> u32 {
+        self.geometry.num_sectors
+    }
+
+    // Sectors the host can see, from the start of the flash
+    pub(crate) fn visible_sectors(&self) -> u32 {
+        self.geometry.num_sectors - JOURNAL_SECTORS - self.crc_table_sectors() - FTL_SPARE_SECTORS
+    }
+
+    // Enough for every sector of the flash, a few more than the visible ones
+    // need
+    #[cfg(feature = "sector-crc")]
+    fn crc_table_sectors(&self) -> u32 {
+        self.geometry
+            .num_sectors
+            .div_ceil(SECTORS_PER_CRC_TABLE_SECTOR)
+    }
+
+    #[cfg(not(feature = "sector-crc"))]
+    fn crc_table_sectors(&self) -> u32 {
+        0
+    }
+
+    #[cfg(feature = "journaled-writes")]
+    fn journal_slot(&self, seq: u32) -> u32 {
+        self.visible_sectors() + seq % JOURNAL_SLOTS
+    }
+
+    #[cfg(feature = "journaled-writes")]
+    fn journal_record_addr(&self, record: u32) -> u32 {
+        (self.visible_sectors() + JOURNAL_SLOTS) * FLASH_SECTOR_SIZE as u32
+            + record * JOURNAL_RECORD_SIZE as u32
+    }
+
+    pub(crate) fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.wake()?;
+        // Sectors may not be contiguous in flash, read them one at a time
+        let mut done = 0;
+        while done < buf.len() {
+            let chunk_addr = addr + done as u32;
+            let len = min(
+                buf.len() - done,
+                FLASH_SECTOR_SIZE - chunk_addr as usize % FLASH_SECTOR_SIZE,
+            );
+            let physical_addr = self.physical_addr(chunk_addr);
+            if self
+                .nvm
+                .read_sector_is_bad(physical_addr / FLASH_SECTOR_SIZE as u32)?
+            {
+                return Err(BlockDeviceError::HardwareError);
+            }
+            self.read_physical(physical_addr, &mut buf[done..done + len])?;
+            done += len;
+        }
+        self.cache.patch(addr, buf);
+        Ok(())
+    }
+
+    // Read within one physical sector
+    fn read_physical(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if self.pending_erase.contains(addr / FLASH_SECTOR_SIZE as u32) {
+            buf.fill(0xff);
+            return Ok(());
+        }
+        self.wake()?;
+        self.flash
+            .get_mut()
+            .read(addr, buf)
+            .map_err(|_| BlockDeviceError::HardwareError)
+    }
+
+    // Make `count` logical blocks from `lba` read back as erased: the whole
+    // disk for `erase_device`, and meant for SCSI UNMAP and WRITE SAME with an
+    // all 0xff block once usbd_scsi passes those down.  Whole sectors are
+    // only noted, in NVM too so that a reset doesn't bring the old contents
+    // back; `erase_pending` gets to them in the background and until then
+    // they read back as erased.  Blocks that only cover part of a sector
+    // (512 byte LBAs) are written with 0xff instead.  The journal only has a
+    // write to replay right after a reset, and `recover_journal` saw to that.
+    pub(crate) fn erase_blocks(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        let end = lba
+            .checked_add(count)
+            .filter(|&end| end <= self.max_lba() + 1)
+            .ok_or(BlockDeviceError::InvalidAddress)?;
+        #[cfg(feature = "sector-crc")]
+        let start = lba;
+        let mut lba = lba;
+        while lba < end {
+            let sector = lba / BLOCKS_PER_SECTOR;
+            if lba % BLOCKS_PER_SECTOR == 0 && end - lba >= BLOCKS_PER_SECTOR {
+                if self.cache.sector == Some(sector) {
+                    self.cache.invalidate();
+                }
+                let physical = self.physical_addr(sector * FLASH_SECTOR_SIZE as u32)
+                    / FLASH_SECTOR_SIZE as u32;
+                if !self.nvm.read_sector_is_erased(physical)? {
+                    self.pending_erase.set(physical);
+                }
+                lba += BLOCKS_PER_SECTOR;
+            } else {
+                self.write_block(lba, &[0xff; LOGICAL_BLOCK_SIZE])?;
+                lba += 1;
+            }
+        }
+        // The CRCs of the noted sectors would no longer match, so their CRC
+        // table sectors start over.  Other sectors sharing them go unchecked
+        // until rewritten.
+        #[cfg(feature = "sector-crc")]
+        {
+            let sectors = start.div_ceil(BLOCKS_PER_SECTOR)..end / BLOCKS_PER_SECTOR;
+            if !sectors.is_empty() {
+                let first = self.crc_slots_addr(sectors.start) / FLASH_SECTOR_SIZE as u32;
+                let last = self.crc_slots_addr(sectors.end - 1) / FLASH_SECTOR_SIZE as u32;
+                for table_sector in first..=last {
+                    self.pending_erase.set(table_sector);
+                }
+            }
+        }
+        self.nvm.save_wipe_map(&self.pending_erase.0)?;
+        self.erase_cursor = 0;
+        self.flush()
+    }
+
+    // Erase the next sector left over by `erase_blocks`.  Returns false once
+    // there is none left.
+    pub(crate) fn erase_pending(&mut self) -> Result<bool, BlockDeviceError> {
+        let Some(sector) = self
+            .pending_erase
+            .first_in(self.erase_cursor..self.num_sectors())
+        else {
+            return Ok(false);
+        };
+        self.erase_cursor = sector;
+        self.erase_physical(sector)?;
+        Ok(true)
+    }
+
+    fn erase_physical(&mut self, sector: u32) -> Result<(), BlockDeviceError> {
+        self.wake()?;
+        self.flash
+            .get_mut()
+            .erase_sectors(sector * FLASH_SECTOR_SIZE as u32, 1)
+            .map_err(|_| BlockDeviceError::EraseError)?;
+        self.erase_no_longer_pending(sector)?;
+        self.nvm
+            .save_sector_is_erased(sector, true)
+            .map_err(|e| e.into())
+    }
+
+    // Called once `sector` was erased, before anything is written to it
+    fn erase_no_longer_pending(&mut self, sector: u32) -> Result<(), BlockDeviceError> {
+        if self.pending_erase.contains(sector) {
+            self.pending_erase.remove(sector);
+            self.nvm.save_sector_wipe_pending(sector, false)?;
+        }
+        Ok(())
+    }
+
+    // Where a logical flash address currently lives
+    #[cfg(not(feature = "wear-leveling"))]
+    fn physical_addr(&self, addr: u32) -> u32 {
+        addr
+    }
+
+    #[cfg(feature = "wear-leveling")]
+    fn physical_addr(&self, addr: u32) -> u32 {
+        let sector = addr / FLASH_SECTOR_SIZE as u32;
+        self.ftl.physical_sector(&self.nvm, sector) * FLASH_SECTOR_SIZE as u32
+            + addr % FLASH_SECTOR_SIZE as u32
+    }
+
+    // Write the cached sector back to flash.  On failure the cached data is
+    // dropped rather than retried on every following write.
+    pub(crate) fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        match self.cache.sector {
+            Some(sector) if self.cache.dirty => {
+                let buf = core::mem::take(&mut self.cache.buf);
+                #[cfg(feature = "sector-crc")]
+                let result = self
+                    .record_crc(sector, CRC_UNKNOWN)
+                    .and_then(|_| self.write_sector(sector, buf))
+                    .and_then(|_| self.record_crc(sector, crc32(buf)));
+                #[cfg(not(feature = "sector-crc"))]
+                let result = self.write_sector(sector, buf);
+                self.cache.buf = buf;
+                if result.is_ok() {
+                    self.cache.dirty = false;
+                    self.programmed = true;
+                } else {
+                    self.cache.invalidate();
+                }
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn write_cache_dirty(&self) -> bool {
+        self.cache.dirty
+    }
+
+    // True if the cache holds data that hasn't been written to since the
+    // previous call
+    pub(crate) fn write_cache_idle(&mut self) -> bool {
+        let idle = self.cache.dirty && !self.cache.touched;
+        self.cache.touched = false;
+        idle
+    }
+
+    // True if data reached the flash since the previous call, for the LED
+    pub(crate) fn take_programmed(&mut self) -> bool {
+        core::mem::take(&mut self.programmed)
+    }
+
+    // True if the host hasn't read or written anything since the previous call
+    pub(crate) fn host_idle(&mut self) -> bool {
+        let idle = !self.host_active;
+        self.host_active = false;
+        idle
+    }
+
+    // Sectors written since boot may hold file data whose FAT entries the
+    // host hasn't written yet, so they only become candidates for pre-erasing
+    // after a reboot
+    pub(crate) fn written_since_boot(&self, sector: u32) -> bool {
+        self.written.contains(sector)
+    }
+
+    // Erase a logical sector whose contents are no longer needed, so the next
+    // write to it takes the fast path.  Returns true if a flash erase was
+    // actually done.
+    pub(crate) fn pre_erase_sector(&mut self, sector: u32) -> Result<bool, BlockDeviceError> {
+        if sector >= self.visible_sectors() || self.cache.sector == Some(sector) {
+            return Ok(false);
+        }
+        // Pool sectors are erased (and counted) by the FTL when allocated
+        #[cfg(feature = "wear-leveling")]
+        if ftl::is_hot(sector) {
+            return Ok(false);
+        }
+        let physical =
+            self.physical_addr(sector * FLASH_SECTOR_SIZE as u32) / FLASH_SECTOR_SIZE as u32;
+        if self.nvm.read_sector_is_erased(physical)? || self.nvm.read_sector_is_bad(physical)? {
+            return Ok(false);
         }
+        if self.is_block_erased(physical)? {
+            self.nvm.save_sector_is_erased(physical, true)?;
+            return Ok(false);
+        }
+        self.erase_sector(sector)?;
+        Ok(true)
+    }
+
+    fn erase_sector(&mut self, sector: u32) -> Result<(), BlockDeviceError> {
+        if self.cache.sector == Some(sector) {
+            self.cache.invalidate();
+        }
+        let physical =
+            self.physical_addr(sector * FLASH_SECTOR_SIZE as u32) / FLASH_SECTOR_SIZE as u32;
+        if self.nvm.read_sector_is_erased(physical)? {
+            return Ok(());
+        }
+        #[cfg(feature = "sector-crc")]
+        self.record_crc(sector, CRC_UNKNOWN)?;
+        #[cfg(feature = "wear-leveling")]
+        if ftl::is_hot(sector) {
+            let pool = self.ftl.pool_sector(&self.nvm, sector);
+            self.ftl.record_erase(&mut self.nvm, pool)?;
+        }
+        self.erase_physical(physical)?;
+        #[cfg(feature = "sector-crc")]
+        self.record_crc(sector, ERASED_SECTOR_CRC)?;
+        Ok(())
     }
 
-    fn is_block_erased(&mut self, lba: u32) -> Result<bool, BlockDeviceError> {
+    // Check the sectors overlapping `len` bytes from `addr` against their
+    // stored CRCs.  Sectors without one (never written since the table was
+    // started, whose table sector had to be erased, or cut off by a reset
+    // half way through a write) pass.
+    #[cfg(feature = "sector-crc")]
+    pub(crate) fn check_crc(&mut self, addr: u32, len: u32) -> Result<bool, BlockDeviceError> {
         // Note: Be mindful of stack usage by keeping this value small
-        const READ_CHUNK_SIZE: usize = 4;
+        const READ_CHUNK_SIZE: usize = 64;
         let mut buffer = [0u8; READ_CHUNK_SIZE];
+        let first = addr / FLASH_SECTOR_SIZE as u32;
+        let last = (addr + len.max(1) - 1) / FLASH_SECTOR_SIZE as u32;
+        for sector in first..=min(last, self.visible_sectors() - 1) {
+            if self.cache.sector == Some(sector) {
+                self.flush()?;
+            }
+            let Some(expected) = self.lookup_crc(sector)? else {
+                continue;
+            };
+            let mut crc = CRC32_INIT;
+            for chunk in (0..FLASH_SECTOR_SIZE).step_by(READ_CHUNK_SIZE) {
+                self.read(
+                    sector * FLASH_SECTOR_SIZE as u32 + chunk as u32,
+                    &mut buffer,
+                )?;
+                crc = crc32_update(crc, &buffer);
+            }
+            if !crc != expected {
+                defmt::error!("CRC mismatch on sector {}", sector);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Without `sector-crc` there is nothing to check against
+    #[cfg(not(feature = "sector-crc"))]
+    pub(crate) fn check_crc(&mut self, _addr: u32, _len: u32) -> Result<bool, BlockDeviceError> {
+        Ok(true)
+    }
+
+    #[cfg(feature = "sector-crc")]
+    fn crc_slots_addr(&self, sector: u32) -> u32 {
+        let table_start = self.visible_sectors() + JOURNAL_SECTORS;
+        table_start * FLASH_SECTOR_SIZE as u32 + sector * (CRC_SLOTS * 4) as u32
+    }
+
+    #[cfg(feature = "sector-crc")]
+    fn lookup_crc(&mut self, sector: u32) -> Result<Option<u32>, BlockDeviceError> {
+        let mut slots = [0u8; CRC_SLOTS * 4];
+        self.read_physical(self.crc_slots_addr(sector), &mut slots)?;
+        Ok(slots
+            .chunks(4)
+            .map(|slot| u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]]))
+            .take_while(|&crc| crc != 0xffff_ffff)
+            .last()
+            .filter(|&crc| crc != CRC_UNKNOWN))
+    }
+
+    #[cfg(feature = "sector-crc")]
+    fn record_crc(&mut self, sector: u32, crc: u32) -> Result<(), BlockDeviceError> {
+        let addr = self.crc_slots_addr(sector);
+        let table_sector = addr / FLASH_SECTOR_SIZE as u32;
+        let mut slots = [0u8; CRC_SLOTS * 4];
+        self.read_physical(addr, &mut slots)?;
+        let free = slots.chunks(4).position(|slot| slot == [0xff; 4]);
+        let slot = match free {
+            Some(slot) if !self.pending_erase.contains(table_sector) => slot,
+            _ => {
+                // Start the table sector over.  The other sectors it covers
+                // lose their CRCs and go unchecked until rewritten.
+                defmt::warn!("Erasing CRC table sector {}", table_sector);
+                self.erase_physical(table_sector)?;
+                0
+            }
+        };
+        if self.nvm.read_sector_is_erased(table_sector)? {
+            self.nvm.save_sector_is_erased(table_sector, false)?;
+        }
+        self.wake()?;
+        self.flash
+            .get_mut()
+            .write_bytes(addr + (slot * 4) as u32, &crc.to_le_bytes())
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
+
+    // March test of one sector: erase and check blank, program a
+    // pattern derived from the address and read it back, then the same with
+    // the complement so that every bit is seen at 0 and 1.  Destroys the
+    // contents, leaves the sector erased and updates its bad sector bit.
+    pub(crate) fn test_sector(&mut self, sector: u32) -> Result<bool, BlockDeviceError> {
+        if !self.testable_sectors().contains(&sector) {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        // The pattern is built in the cache buffer
+        self.flush()?;
+        self.cache.invalidate();
+        #[cfg(feature = "sector-crc")]
+        self.record_crc(sector, CRC_UNKNOWN)?;
+        let buf = core::mem::take(&mut self.cache.buf);
+        let result = self.march(sector, buf);
+        self.cache.buf = buf;
+        // A failed erase or program is as bad as a failed read back
+        let passed = result.unwrap_or(false);
+        if !passed {
+            defmt::warn!("Sector {} failed self-test", sector);
+        }
+        self.nvm.save_sector_is_bad(sector, !passed)?;
+        // Its CRC would no longer match
+        #[cfg(feature = "sector-crc")]
+        self.record_crc(sector, ERASED_SECTOR_CRC)?;
+        Ok(passed)
+    }
+
+    // Sectors the self-test may destroy: those the host sees, except where
+    // the FTL keeps hot sectors (their home positions are pool sectors too).
+    // All of them are at the same place physically and logically.
+    pub(crate) fn testable_sectors(&self) -> Range<u32> {
+        #[cfg(feature = "wear-leveling")]
+        let first = ftl::HOT_SECTORS;
+        #[cfg(not(feature = "wear-leveling"))]
+        let first = 0;
+        first..self.visible_sectors()
+    }
+
+    fn march(&mut self, sector: u32, buf: &mut [u8]) -> Result<bool, BlockDeviceError> {
+        let base = sector * FLASH_SECTOR_SIZE as u32;
+        for invert in [0x00, 0xff] {
+            self.erase_physical(sector)?;
+            if !self.is_block_erased(sector)? {
+                return Ok(false);
+            }
+            for (i, byte) in buf.iter_mut().enumerate() {
+                let addr = base + i as u32;
+                *byte = (addr ^ (addr >> 8) ^ (addr >> 16)) as u8 ^ invert;
+            }
+            self.nvm.save_sector_is_erased(sector, false)?;
+            self.write_block_fast(sector, buf)?;
+            if !self.compare_range(base, buf)? {
+                return Ok(false);
+            }
+        }
+        self.erase_physical(sector)?;
+        self.is_block_erased(sector)
+    }
+
+    // Replace a whole logical sector with what `fill` puts in a buffer of
+    // 0xff.  `fill` also gets the NVM, e.g. to report from it.
+    pub(crate) fn rewrite_sector(
+        &mut self,
+        sector: u32,
+        fill: impl FnOnce(&mut [u8], &Nvm),
+    ) -> Result<(), BlockDeviceError> {
+        if sector >= self.visible_sectors() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        self.flush()?;
+        self.cache.sector = Some(sector);
+        self.cache.buf.fill(0xff);
+        fill(self.cache.buf, &self.nvm);
+        self.cache.dirty = true;
+        self.flush()
+    }
+
+    // Like `rewrite_sector`, but `fill` starts from what is in the sector
+    pub(crate) fn update_sector(
+        &mut self,
+        sector: u32,
+        fill: impl FnOnce(&mut [u8], &Nvm),
+    ) -> Result<(), BlockDeviceError> {
+        if sector >= self.visible_sectors() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        self.flush()?;
+        let buf = core::mem::take(&mut self.cache.buf);
+        let result = self.read(sector * FLASH_SECTOR_SIZE as u32, buf);
+        self.cache.buf = buf;
+        result?;
+        self.cache.sector = Some(sector);
+        fill(self.cache.buf, &self.nvm);
+        self.cache.dirty = true;
+        self.flush()
+    }
+
+    pub(crate) fn nvm_mut(&mut self) -> &mut Nvm {
+        &mut self.nvm
+    }
+
+    // Bring the NVM erased bit of a physical sector in line with what is
+    // actually in flash.  Unless rebuilding, only sectors the map claims to be
+    // erased are read, since a sector wrongly marked as not erased only costs
+    // an extra erase.
+    pub(crate) fn check_erased_sector(
+        &mut self,
+        sector: u32,
+        rebuild: bool,
+    ) -> Result<(), BlockDeviceError> {
+        let claimed = self.nvm.read_sector_is_erased(sector)?;
+        if !claimed && !rebuild {
+            return Ok(());
+        }
+        let erased = self.is_block_erased(sector)?;
+        if erased != claimed {
+            if !rebuild {
+                defmt::warn!("sector {} marked erased but is not", sector);
+            }
+            self.nvm.save_sector_is_erased(sector, erased)?;
+        }
+        Ok(())
+    }
+
+    fn is_block_erased(&mut self, sector: u32) -> Result<bool, BlockDeviceError> {
+        self.is_range_erased(sector * FLASH_SECTOR_SIZE as u32, FLASH_SECTOR_SIZE)
+    }
+
+    fn is_range_erased(&mut self, addr: u32, len: usize) -> Result<bool, BlockDeviceError> {
+        // Note: Be mindful of stack usage by keeping this value small.  Reads
+        // this long go through DMA, so a sector takes 32 transactions.
+        const READ_CHUNK_SIZE: usize = 128;
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        self.wake()?;
         let flash = self.flash.get_mut();
-        let block_bytes: u32 = Self::BLOCK_BYTES.try_into().unwrap();
-        for chunk in (0..FLASH_SECTOR_SIZE).step_by(READ_CHUNK_SIZE) {
+        for chunk in (0..len).step_by(READ_CHUNK_SIZE) {
+            let buffer = &mut buffer[..min(READ_CHUNK_SIZE, len - chunk)];
             flash
-                .read(lba * block_bytes + chunk as u32, &mut buffer)
+                .read(addr + chunk as u32, buffer)
                 .map_err(|_| BlockDeviceError::HardwareError)?;
-            if buffer != [0xff; READ_CHUNK_SIZE] {
+            if buffer.iter().any(|&b| b != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "wear-leveling"))]
+    fn write_sector(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        self.program_sector(sector, data)
+    }
+
+    // Hot sectors are written to a fresh pool sector and only then remapped,
+    // so a reset half way leaves the previous copy in place
+    #[cfg(feature = "wear-leveling")]
+    fn write_sector(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        if !ftl::is_hot(sector) {
+            return self.program_sector(sector, data);
+        }
+        let pool = self
+            .ftl
+            .allocate(&self.nvm)
+            .ok_or(BlockDeviceError::WriteError)?;
+        let physical = self.ftl.pool_to_physical(pool);
+        if !self.nvm.read_sector_is_erased(physical)? {
+            self.ftl.record_erase(&mut self.nvm, pool)?;
+        }
+        self.program_sector(physical, data)?;
+        self.ftl
+            .commit(&mut self.nvm, sector, pool)
+            .map_err(|e| e.into())
+    }
+
+    // Program a whole physical flash sector, erasing it first unless the NVM
+    // map says it is already erased
+    fn program_sector(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        if self.nvm.read_sector_is_bad(sector)? {
+            return Err(BlockDeviceError::WriteError);
+        }
+        if self.nvm.read_sector_is_erased(sector)? {
+            // Clear the erased bit before programming: if the write fails half
+            // way the sector is no longer erased either
+            self.nvm.save_sector_is_erased(sector, false)?;
+            self.write_block_fast(sector, data)?;
+        } else {
+            self.write_block_replace(sector, data)?;
+        }
+
+        if !self.verify_range(sector * FLASH_SECTOR_SIZE as u32, data)? {
+            defmt::warn!("verify failed on sector {}, retrying", sector);
+            self.write_block_replace(sector, data)?;
+            if !self.verify_range(sector * FLASH_SECTOR_SIZE as u32, data)? {
+                defmt::error!("verify failed on sector {}", sector);
+                return Err(BlockDeviceError::WriteError);
+            }
+        }
+        Ok(())
+    }
+
+    // Compare programmed flash against the data the host sent us
+    #[cfg(feature = "verify-writes")]
+    fn verify_range(&mut self, addr: u32, data: &[u8]) -> Result<bool, BlockDeviceError> {
+        self.compare_range(addr, data)
+    }
+
+    // Without `verify-writes` we take the flash's word for it
+    #[cfg(not(feature = "verify-writes"))]
+    fn verify_range(&mut self, _addr: u32, _data: &[u8]) -> Result<bool, BlockDeviceError> {
+        Ok(true)
+    }
+
+    fn compare_range(&mut self, addr: u32, data: &[u8]) -> Result<bool, BlockDeviceError> {
+        // Note: Be mindful of stack usage by keeping this value small
+        const READ_CHUNK_SIZE: usize = 32;
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        self.wake()?;
+        let flash = self.flash.get_mut();
+        for (i, expected) in data.chunks(READ_CHUNK_SIZE).enumerate() {
+            let buffer = &mut buffer[..expected.len()];
+            flash
+                .read(addr + (i * READ_CHUNK_SIZE) as u32, buffer)
+                .map_err(|_| BlockDeviceError::HardwareError)?;
+            if buffer != expected {
                 return Ok(false);
             }
         }
@@ -107,48 +880,247 @@ impl<'a> SpiFlash<'a> {
     }
 
     // Write only (Assumes chip has been erased already)
-    fn write_block_fast(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
-        defmt::info!("write_block_fast {}, block size: {}", lba, block.len());
+    fn write_block_fast(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        defmt::trace!("write_block_fast {}, block size: {}", sector, data.len());
+        if data.len() != FLASH_SECTOR_SIZE {
+            return Err(BlockDeviceError::WriteError);
+        }
 
         // write
+        self.wake()?;
+        self.flash
+            .get_mut()
+            .write_bytes(sector * FLASH_SECTOR_SIZE as u32, data)
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
+
+    // The host often fills a freshly erased sector one block at a time, in
+    // which case the block can be programmed in place instead of erasing and
+    // rewriting the whole sector.  Returns false if it has to be rewritten
+    // after all.
+    #[cfg(not(feature = "write-back-cache"))]
+    fn write_in_place(
+        &mut self,
+        sector: u32,
+        offset: usize,
+        block: &[u8],
+    ) -> Result<bool, BlockDeviceError> {
+        // Hot sectors always move to a fresh pool sector
+        #[cfg(feature = "wear-leveling")]
+        if ftl::is_hot(sector) {
+            return Ok(false);
+        }
+        let physical =
+            self.physical_addr(sector * FLASH_SECTOR_SIZE as u32) / FLASH_SECTOR_SIZE as u32;
+        let addr = physical * FLASH_SECTOR_SIZE as u32 + offset as u32;
+        if self.pending_erase.contains(physical)
+            || self.nvm.read_sector_is_bad(physical)?
+            || !self.is_range_erased(addr, block.len())?
+        {
+            return Ok(false);
+        }
+        // The recorded CRC stops matching with the first block, the real one
+        // is recorded once the last block is in
+        #[cfg(feature = "sector-crc")]
+        if self.lookup_crc(sector)?.is_some() {
+            self.record_crc(sector, CRC_UNKNOWN)?;
+        }
+        if self.nvm.read_sector_is_erased(physical)? {
+            self.nvm.save_sector_is_erased(physical, false)?;
+        }
+        self.wake()?;
         self.flash
             .get_mut()
-            .write_bytes(lba * Self::BLOCK_BYTES as u32, block)
-            .ok();
+            .write_bytes(addr, block)
+            .map_err(|_| BlockDeviceError::WriteError)?;
+        if !self.verify_range(addr, block)? {
+            defmt::warn!("verify failed at 0x{:x}, rewriting sector", addr);
+            return Ok(false);
+        }
+        #[cfg(feature = "sector-crc")]
+        if offset + block.len() == FLASH_SECTOR_SIZE {
+            self.record_crc(sector, crc32(self.cache.buf))?;
+        }
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "journaled-writes"))]
+    fn write_block_replace(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_block_slow(sector, data)
+    }
+
+    // Erase + write through the journal.  The new data is staged in the next
+    // journal slot and a record naming the target is logged before the target
+    // is erased, so a reset at any point leaves the old data in place or the
+    // new data ready to replay.
+    #[cfg(feature = "journaled-writes")]
+    fn write_block_replace(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        let seq = self.journal_seq.wrapping_add(1);
+        let slot = self.journal_slot(seq);
+        if self.nvm.read_sector_is_erased(slot)? {
+            self.nvm.save_sector_is_erased(slot, false)?;
+            self.write_block_fast(slot, data)?;
+        } else {
+            self.write_block_slow(slot, data)?;
+        }
+        if !self.verify_range(slot * FLASH_SECTOR_SIZE as u32, data)? {
+            return Err(BlockDeviceError::WriteError);
+        }
+
+        if self.journal_records == JOURNAL_RECORDS {
+            // Start the log over.  Only its last record could matter, and
+            // that write has finished or already failed.
+            let log = self.journal_record_addr(0) / FLASH_SECTOR_SIZE as u32;
+            self.erase_physical(log)?;
+            self.journal_records = 0;
+        }
+        let addr = self.journal_record_addr(self.journal_records);
+        let log = addr / FLASH_SECTOR_SIZE as u32;
+        if self.nvm.read_sector_is_erased(log)? {
+            self.nvm.save_sector_is_erased(log, false)?;
+        }
+        let mut record = [0u8; 16];
+        record[0..4].copy_from_slice(&seq.to_le_bytes());
+        record[4..8].copy_from_slice(&sector.to_le_bytes());
+        record[8..12].copy_from_slice(&crc32(data).to_le_bytes());
+        let check = crc32(&record[..12]);
+        record[12..16].copy_from_slice(&check.to_le_bytes());
+        self.wake()?;
+        self.flash
+            .get_mut()
+            .write_bytes(addr, &record)
+            .map_err(|_| BlockDeviceError::WriteError)?;
+        self.journal_seq = seq;
+        self.journal_records += 1;
+
+        self.write_block_slow(sector, data)?;
+        self.flash
+            .get_mut()
+            .write_bytes(addr + JOURNAL_DONE_OFFSET, &[0; 4])
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
+
+    // Find where the journal log ends and finish a journaled write that was
+    // interrupted by a reset
+    #[cfg(feature = "journaled-writes")]
+    fn recover_journal(&mut self) -> Result<(), BlockDeviceError> {
+        self.wake()?;
+        let mut pending = None;
+        for record in 0..JOURNAL_RECORDS {
+            let addr = self.journal_record_addr(record);
+            let mut buf = [0u8; JOURNAL_RECORD_SIZE];
+            self.flash
+                .get_mut()
+                .read(addr, &mut buf)
+                .map_err(|_| BlockDeviceError::HardwareError)?;
+            let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+            if buf[..16] == [0xff; 16] {
+                break;
+            }
+            // Whatever was programmed takes up the record, valid or not
+            self.journal_records = record + 1;
+            if word(12) != crc32(&buf[..12]) {
+                defmt::warn!("Torn journal record {}", record);
+                continue;
+            }
+            self.journal_seq = word(0);
+            let done = word(JOURNAL_DONE_OFFSET as usize) != 0xffff_ffff;
+            pending = (!done).then_some((addr, word(4), word(8)));
+        }
+        let Some((addr, sector, crc)) = pending else {
+            return Ok(());
+        };
+        if sector >= self.num_sectors() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        defmt::warn!("Replaying journaled write to sector {}", sector);
+        let slot = self.journal_slot(self.journal_seq);
+        let buf = core::mem::take(&mut self.cache.buf);
+        let result = self
+            .flash
+            .get_mut()
+            .read(slot * FLASH_SECTOR_SIZE as u32, buf)
+            .map_err(|_| BlockDeviceError::HardwareError)
+            .and_then(|_| {
+                // The record is only logged once the data is in place
+                if crc32(buf) != crc {
+                    return Err(BlockDeviceError::HardwareError);
+                }
+                self.write_block_slow(sector, buf)
+            });
+        self.cache.buf = buf;
+        result?;
+        self.flash
+            .get_mut()
+            .write_bytes(addr + JOURNAL_DONE_OFFSET, &[0; 4])
+            .map_err(|_| BlockDeviceError::WriteError)?;
+        // The write was cut off before its CRC was recorded.  Pool sectors
+        // weren't remapped yet, so only sectors at home get one.
+        #[cfg(feature = "sector-crc")]
+        if sector < self.visible_sectors() {
+            #[cfg(feature = "wear-leveling")]
+            if ftl::is_hot(sector) {
+                return Ok(());
+            }
+            self.record_crc(sector, crc)?;
+        }
         Ok(())
     }
 
     // Erase + write
-    fn write_block_slow(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
-
-        defmt::info!("write_block_slow {}", lba);
-        if block.len() != FLASH_SECTOR_SIZE {
+    fn write_block_slow(&mut self, sector: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        defmt::trace!("write_block_slow {}", sector);
+        if data.len() != FLASH_SECTOR_SIZE {
             return Err(BlockDeviceError::WriteError);
         }
         // erase
+        self.wake()?;
         self.flash
             .get_mut()
-            .erase_sectors(lba * Self::BLOCK_BYTES as u32, 1)
+            .erase_sectors(sector * FLASH_SECTOR_SIZE as u32, 1)
             .map_err(|_| BlockDeviceError::EraseError)?;
+        self.erase_no_longer_pending(sector)?;
 
         // write
         self.flash
             .get_mut()
-            .write_bytes(lba * Self::BLOCK_BYTES as u32, &block)
-            .ok();
+            .write_bytes(sector * FLASH_SECTOR_SIZE as u32, data)
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
 
+    // Put the flash in deep power-down unless it was used since the previous
+    // call.  Any flash access wakes it up again.
+    pub(crate) fn power_down_if_idle(&mut self) -> Result<(), BlockDeviceError> {
+        if !self.accessed && !self.powered_down {
+            flash_command(&mut self.spi, &mut self.cs, &mut [CMD_POWER_DOWN], &mut [])?;
+            self.powered_down = true;
+        }
+        self.accessed = false;
         Ok(())
     }
 
-    // pub(crate) fn sleep(self: &mut Self) {
-    //     self.flash.sleep().unwrap();
-    // }
+    // Called before anything that talks to the flash
+    fn wake(&mut self) -> Result<(), BlockDeviceError> {
+        self.accessed = true;
+        if self.powered_down {
+            flash_command(
+                &mut self.spi,
+                &mut self.cs,
+                &mut [CMD_RELEASE_POWER_DOWN],
+                &mut [],
+            )?;
+            cortex_m::asm::delay(RELEASE_POWER_DOWN_CYCLES);
+            self.powered_down = false;
+        }
+        Ok(())
+    }
 
-    #[allow(dead_code)]
     pub(crate) fn check_flash_id(self: &mut Self) -> Result<(), LightNoteErrors> {
+        self.wake()
+            .map_err(|_| LightNoteErrors::FailedToReadFlashID)?;
         for _ in 0..20 {
             if let Ok(id) = self.flash.get_mut().read_jedec_id() {
-                if id.device_id() == [0x40, 0x18] {
+                if id.device_id() == &self.geometry.jedec_id[1..] {
                     return Ok(());
                 }
             }
@@ -157,24 +1129,46 @@ impl<'a> SpiFlash<'a> {
     }
 }
 
-type SpiFlashMainType<'a> = SpiProxy<
-    'a,
-    NullMutex<
-        stm32l0xx_hal::spi::Spi<
-            stm32l0xx_hal::pac::SPI1,
-            (
-                PB3<stm32l0xx_hal::gpio::Analog>,
-                PB4<stm32l0xx_hal::gpio::Analog>,
-                PB5<stm32l0xx_hal::gpio::Analog>,
-            ),
-        >,
-    >,
->;
-
-type SpiFlashWithCsType<'a> =
-    Flash<SpiFlashMainType<'a>, PB6<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>>;
-
-pub struct SpiFlash<'a> {
-    flash: RefCell<SpiFlashWithCsType<'a>>,
+// A command the w25q driver doesn't offer, with the response (if any) read
+// into `response`
+pub(crate) fn flash_command(
+    spi: &mut SpiProxy,
+    cs: &mut impl OutputPin,
+    cmd: &mut [u8],
+    response: &mut [u8],
+) -> Result<(), BlockDeviceError> {
+    cs.set_low().map_err(|_| BlockDeviceError::HardwareError)?;
+    let result = spi.transfer(cmd).and_then(|_| spi.transfer(response));
+    cs.set_high().ok();
+    result
+        .map(|_| ())
+        .map_err(|_| BlockDeviceError::HardwareError)
+}
+
+type SpiFlashWithCsType = Flash<SpiProxy, FlashCs>;
+
+pub struct SpiFlash {
+    flash: RefCell<SpiFlashWithCsType>,
+    #[cfg(feature = "wear-leveling")]
+    ftl: Ftl,
+    geometry: FlashGeometry,
+    // For commands the driver doesn't have, on the same chip select
+    spi: SpiProxy,
+    cs: FlashCs,
+    powered_down: bool,
+    accessed: bool,
     nvm: Nvm,
+    host_active: bool,
+    programmed: bool,
+    written: SectorSet,
+    // Physical sectors `erase_blocks` promised to erase (mirrored in NVM),
+    // and how far `erase_pending` got through them
+    pending_erase: SectorSet,
+    erase_cursor: u32,
+    // Last journal sequence number used, and records used in the journal log
+    #[cfg(feature = "journaled-writes")]
+    journal_seq: u32,
+    #[cfg(feature = "journaled-writes")]
+    journal_records: u32,
+    cache: SectorCache,
 }
@@ -0,0 +1,121 @@
+// Wear leveling for the sectors FAT rewrites all the time.
+//
+// The first HOT_SECTORS logical sectors (boot sector, FATs, root directory)
+// are not stored at a fixed place.  Each rewrite goes to the least worn free
+// sector of a pool made of the hot sectors' own home positions plus
+// SPARE_SECTORS spare sectors hidden from the host at the end of the flash.
+// The map from logical sector to pool sector lives in EEPROM and is only
+// updated after the new copy has been programmed, so a reset at any point
+// leaves either the old or the new copy mapped.  All other sectors map 1:1.
+
+use crate::nvm::{self, Nvm};
+
+pub(crate) const HOT_SECTORS: u32 = 128;
+pub(crate) const SPARE_SECTORS: u32 = 32;
+pub(crate) const POOL_SECTORS: u32 = HOT_SECTORS + SPARE_SECTORS;
+
+pub(crate) fn is_hot(sector: u32) -> bool {
+    sector < HOT_SECTORS
+}
+
+pub(crate) struct Ftl {
+    // One bit per pool sector currently holding a logical sector
+    in_use: [u32; (POOL_SECTORS as usize + 31) / 32],
+    // First spare sector, depends on the size of the flash
+    spare_start: u32,
+}
+
+impl Ftl {
+    pub(crate) fn new(nvm: &Nvm, num_sectors: u32) -> Self {
+        let mut ftl = Self {
+            in_use: [0; (POOL_SECTORS as usize + 31) / 32],
+            spare_start: num_sectors - SPARE_SECTORS,
+        };
+        for sector in 0..HOT_SECTORS {
+            let pool = ftl.pool_sector(nvm, sector);
+            if ftl.is_in_use(pool) {
+                defmt::warn!("ftl: pool sector {} mapped twice", pool);
+            }
+            ftl.set_in_use(pool, true);
+        }
+        ftl
+    }
+
+    // Sectors that never moved (blank or unreadable map entry) sit at their
+    // home position
+    pub(crate) fn pool_sector(&self, nvm: &Nvm, sector: u32) -> u32 {
+        match nvm.read_ftl_map(sector) {
+            Ok(Some(pool)) if pool < POOL_SECTORS => pool,
+            _ => sector,
+        }
+    }
+
+    pub(crate) fn physical_sector(&self, nvm: &Nvm, sector: u32) -> u32 {
+        if is_hot(sector) {
+            self.pool_to_physical(self.pool_sector(nvm, sector))
+        } else {
+            sector
+        }
+    }
+
+    pub(crate) fn pool_to_physical(&self, pool: u32) -> u32 {
+        if pool < HOT_SECTORS {
+            pool
+        } else {
+            self.spare_start + (pool - HOT_SECTORS)
+        }
+    }
+
+    // Least worn free pool sector, preferring ones that are already erased
+    pub(crate) fn allocate(&self, nvm: &Nvm) -> Option<u32> {
+        let mut best: Option<(bool, u32, u32)> = None;
+        for pool in 0..POOL_SECTORS {
+            if self.is_in_use(pool)
+                || nvm
+                    .read_sector_is_bad(self.pool_to_physical(pool))
+                    .unwrap_or(true)
+            {
+                continue;
+            }
+            let erased = nvm
+                .read_sector_is_erased(self.pool_to_physical(pool))
+                .unwrap_or(false);
+            let candidate = (!erased, nvm.read_erase_count(pool), pool);
+            if best.map_or(true, |best| candidate < best) {
+                best = Some(candidate);
+            }
+        }
+        best.map(|(_, _, pool)| pool)
+    }
+
+    pub(crate) fn record_erase(&mut self, nvm: &mut Nvm, pool: u32) -> Result<(), nvm::Error> {
+        let count = nvm.read_erase_count(pool);
+        nvm.save_erase_count(pool, count.saturating_add(1))
+    }
+
+    // Point `sector` at `pool`.  This is the commit point of a relocation.
+    pub(crate) fn commit(
+        &mut self,
+        nvm: &mut Nvm,
+        sector: u32,
+        pool: u32,
+    ) -> Result<(), nvm::Error> {
+        let old = self.pool_sector(nvm, sector);
+        nvm.save_ftl_map(sector, pool)?;
+        self.set_in_use(old, false);
+        self.set_in_use(pool, true);
+        Ok(())
+    }
+
+    fn is_in_use(&self, pool: u32) -> bool {
+        self.in_use[pool as usize / 32] & (1 << (pool % 32)) != 0
+    }
+
+    fn set_in_use(&mut self, pool: u32, in_use: bool) {
+        if in_use {
+            self.in_use[pool as usize / 32] |= 1 << (pool % 32);
+        } else {
+            self.in_use[pool as usize / 32] &= !(1 << (pool % 32));
+        }
+    }
+}
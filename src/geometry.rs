@@ -0,0 +1,171 @@
+// Flash size and capabilities, read from the chip at boot.
+//
+// The JEDEC ID tells us who made the part and, by convention, log2 of its
+// size in bytes.  The SFDP basic flash parameter table (JESD216), when the
+// part has one, is the authoritative source for size and erase types.  Both
+// are read with raw commands, since the w25q driver has no SFDP support.
+//
+// Only the capacity is kept, and 4 KiB erase and 3 byte address support are
+// checked.  Larger block erases and fast-read modes are not looked at:
+// sectors are only ever erased one at a time, and SPI1 has a single data
+// line each way with no dummy-cycle reads in the driver, so neither would be
+// used.
+
+use stm32l0xx_hal::prelude::OutputPin;
+
+use crate::{
+    errors::LightNoteErrors,
+    flash::{flash_command, FLASH_SECTOR_SIZE},
+    nvm::MAX_FLASH_SECTORS,
+    spi_bus::SpiProxy,
+};
+
+const CMD_READ_JEDEC_ID: u8 = 0x9f;
+const CMD_READ_SFDP: u8 = 0x5a;
+const SFDP_SIGNATURE: u32 = 0x5044_4653; // "SFDP"
+const SFDP_BASIC_TABLE_ID: u8 = 0x00;
+// DWORDs 1 and 2 of the basic table cover everything we look at
+const SFDP_BASIC_TABLE_DWORDS: usize = 2;
+
+// 1 MiB, comfortably more than the sectors we hide from the host
+const MIN_FLASH_SECTORS: u32 = 256;
+
+// Manufacturer and memory type bytes of the parts we know to work
+const KNOWN_PARTS: [[u8; 2]; 2] = [
+    [0xef, 0x40], // Winbond W25QxxJV-IQ
+    [0xef, 0x70], // Winbond W25QxxJV-IM
+];
+
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct FlashGeometry {
+    pub(crate) jedec_id: [u8; 3],
+    pub(crate) num_sectors: u32,
+}
+
+impl FlashGeometry {
+    // Identify the flash, refusing parts we don't know and parts larger than
+    // we can address or keep track of in NVM (see `MAX_FLASH_SECTORS`)
+    pub(crate) fn probe(
+        spi: &mut SpiProxy,
+        cs: &mut impl OutputPin,
+    ) -> Result<Self, LightNoteErrors> {
+        let mut id = [CMD_READ_JEDEC_ID, 0, 0, 0];
+        command(spi, cs, &mut id, &mut [])?;
+        let jedec_id = [id[1], id[2], id[3]];
+        if !KNOWN_PARTS.contains(&[id[1], id[2]]) || id[3] >= 32 {
+            defmt::error!("Unknown flash, JEDEC ID {:x}", jedec_id);
+            return Err(LightNoteErrors::UnknownFlash);
+        }
+
+        let mut geometry = FlashGeometry {
+            jedec_id,
+            num_sectors: (1u32 << id[3]) / FLASH_SECTOR_SIZE as u32,
+        };
+        match read_basic_table(spi, cs)? {
+            Some(table) => geometry.apply_basic_table(&table)?,
+            None => defmt::warn!("Flash has no SFDP, going by its JEDEC ID"),
+        }
+
+        if geometry.num_sectors < MIN_FLASH_SECTORS {
+            defmt::error!("Flash has only {} sectors", geometry.num_sectors);
+            return Err(LightNoteErrors::UnknownFlash);
+        }
+        if geometry.num_sectors > MAX_FLASH_SECTORS {
+            defmt::error!(
+                "Flash has {} sectors, only {} supported",
+                geometry.num_sectors,
+                MAX_FLASH_SECTORS
+            );
+            return Err(LightNoteErrors::FlashTooLarge);
+        }
+        defmt::info!("Flash geometry: {}", geometry);
+        Ok(geometry)
+    }
+
+    fn apply_basic_table(
+        &mut self,
+        table: &[u32; SFDP_BASIC_TABLE_DWORDS],
+    ) -> Result<(), LightNoteErrors> {
+        // We only ever erase 4 KiB sectors one at a time
+        if table[0] & 0b11 != 0b01 {
+            defmt::error!("Flash has no 4 KiB erase");
+            return Err(LightNoteErrors::UnknownFlash);
+        }
+        // The w25q driver only sends 3 byte addresses
+        if (table[0] >> 17) & 0b11 == 0b10 {
+            defmt::error!("Flash only takes 4 byte addresses");
+            return Err(LightNoteErrors::UnknownFlash);
+        }
+
+        // Density is in bits, either N - 1 or 2^N with bit 31 set
+        let density = table[1];
+        let size_bits: u64 = if density & (1 << 31) == 0 {
+            density as u64 + 1
+        } else {
+            1u64.checked_shl(density & !(1 << 31)).unwrap_or(u64::MAX)
+        };
+        let num_sectors = (size_bits / 8 / FLASH_SECTOR_SIZE as u64).min(u32::MAX as u64) as u32;
+        if num_sectors != self.num_sectors {
+            defmt::warn!(
+                "JEDEC ID says {} sectors, SFDP {}",
+                self.num_sectors,
+                num_sectors
+            );
+            self.num_sectors = num_sectors;
+        }
+
+        Ok(())
+    }
+}
+
+// Locate and read the basic flash parameter table.  None if the part has no
+// SFDP at all.
+fn read_basic_table(
+    spi: &mut SpiProxy,
+    cs: &mut impl OutputPin,
+) -> Result<Option<[u32; SFDP_BASIC_TABLE_DWORDS]>, LightNoteErrors> {
+    let mut header = [0u8; 16];
+    read_sfdp(spi, cs, 0, &mut header)?;
+    if u32::from_le_bytes([header[0], header[1], header[2], header[3]]) != SFDP_SIGNATURE {
+        return Ok(None);
+    }
+    // JESD216 requires the first parameter header to be the basic table
+    let parameter = &header[8..16];
+    if parameter[0] != SFDP_BASIC_TABLE_ID || parameter[7] != 0xff {
+        return Ok(None);
+    }
+    let dwords = (parameter[3] as usize).min(SFDP_BASIC_TABLE_DWORDS);
+    let pointer = u32::from_le_bytes([parameter[4], parameter[5], parameter[6], 0]);
+
+    let mut raw = [0xffu8; SFDP_BASIC_TABLE_DWORDS * 4];
+    read_sfdp(spi, cs, pointer, &mut raw[..dwords * 4])?;
+    let mut table = [0u32; SFDP_BASIC_TABLE_DWORDS];
+    for (dword, bytes) in table.iter_mut().zip(raw.chunks(4)) {
+        *dword = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    // DWORDs past the end of a short table read as 0
+    for dword in table.iter_mut().skip(dwords) {
+        *dword = 0;
+    }
+    Ok(Some(table))
+}
+
+fn read_sfdp(
+    spi: &mut SpiProxy,
+    cs: &mut impl OutputPin,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), LightNoteErrors> {
+    let [_, a2, a1, a0] = addr.to_be_bytes();
+    // Address followed by one dummy byte
+    command(spi, cs, &mut [CMD_READ_SFDP, a2, a1, a0, 0], buf)
+}
+
+fn command(
+    spi: &mut SpiProxy,
+    cs: &mut impl OutputPin,
+    cmd: &mut [u8],
+    response: &mut [u8],
+) -> Result<(), LightNoteErrors> {
+    flash_command(spi, cs, cmd, response).map_err(|_| LightNoteErrors::FailedToReadFlashID)
+}
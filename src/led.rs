@@ -0,0 +1,105 @@
+// Patterns played on led_b by the `led_handler` task.  Other tasks queue them
+// with `try_send` and never wait on LED timing; if the queue is full the
+// pattern is dropped, which is also what rate-limits USB activity blinks.
+// Errors are latched instead (see `raise`) and repeated from then on.
+
+use core::cell::Cell;
+
+use cortex_m::interrupt::{self, Mutex};
+use rtic_monotonics::stm32::ExtU64;
+use rtic_sync::channel::Sender;
+use stm32l0xx_hal::{
+    delay::Delay,
+    prelude::{_embedded_hal_blocking_delay_DelayMs, OutputPin},
+};
+
+use crate::{errors::LightNoteErrors, Mono};
+
+pub(crate) const LED_Q_CAPACITY: usize = 2;
+
+pub(crate) type LedSender = Sender<'static, LedPattern, LED_Q_CAPACITY>;
+
+#[derive(Clone, Copy)]
+pub(crate) enum LedPattern {
+    // A short flash, at most every 100 ms however busy the bus is
+    UsbActivity,
+    // Data going into flash
+    Writing,
+    // Three quick flashes
+    LowBattery,
+    // The code's tens digit, a pause, then the ones digit, where a long
+    // blink counts five and a quick flash is a zero
+    Error(LightNoteErrors),
+}
+
+// The last error raised, kept out of the queue so that activity blinks can't
+// crowd it out
+static LATCHED_ERROR: Mutex<Cell<Option<LightNoteErrors>>> = Mutex::new(Cell::new(None));
+
+pub(crate) fn raise(sender: &mut LedSender, error: LightNoteErrors) {
+    interrupt::free(|cs| LATCHED_ERROR.borrow(cs).set(Some(error)));
+    // Wakes up `led_handler` if it is waiting for a pattern.  If the queue is
+    // full it gets round to the latch anyway.
+    sender.try_send(LedPattern::Error(error)).ok();
+}
+
+pub(crate) fn latched_error() -> Option<LightNoteErrors> {
+    interrupt::free(|cs| LATCHED_ERROR.borrow(cs).get())
+}
+
+pub(crate) async fn play(led: &mut impl OutputPin, pattern: LedPattern) {
+    match pattern {
+        LedPattern::UsbActivity => blink(led, 10, 90).await,
+        LedPattern::Writing => blink(led, 200, 100).await,
+        LedPattern::LowBattery => {
+            for _ in 0..3 {
+                blink(led, 50, 200).await;
+            }
+            Mono::delay(1_000u64.millis()).await;
+        }
+        LedPattern::Error(error) => {
+            blink_digit(led, error as u8 / 10).await;
+            Mono::delay(2_000u64.millis()).await;
+            blink_digit(led, error as u8 % 10).await;
+            Mono::delay(5_000u64.millis()).await;
+        }
+    }
+}
+
+async fn blink_digit(led: &mut impl OutputPin, digit: u8) {
+    for (on_ms, off_ms) in digit_blinks(digit) {
+        blink(led, on_ms as u64, off_ms as u64).await;
+    }
+}
+
+// A long blink for each five, then a short one for each one.  A zero would be
+// nothing at all, so it gets a quick flash.
+fn digit_blinks(digit: u8) -> impl Iterator<Item = (u32, u32)> {
+    let zero = core::iter::repeat((100, 900)).take((digit == 0) as usize);
+    let fives = core::iter::repeat((2_000, 500)).take(digit as usize / 5);
+    let ones = core::iter::repeat((500, 500)).take(digit as usize % 5);
+    zero.chain(fives).chain(ones)
+}
+
+// For errors that stop the firmware before the tasks run: plays
+// `LedPattern::Error` forever with a blocking delay
+pub(crate) fn halt(led: &mut impl OutputPin, error: LightNoteErrors, delay: &mut Delay) -> ! {
+    loop {
+        for (digit, pause_ms) in [(error as u8 / 10, 2_000u32), (error as u8 % 10, 5_000)] {
+            for (on_ms, off_ms) in digit_blinks(digit) {
+                led.set_high().ok();
+                delay.delay_ms(on_ms);
+                led.set_low().ok();
+                delay.delay_ms(off_ms);
+            }
+            delay.delay_ms(pause_ms);
+        }
+    }
+}
+
+async fn blink(led: &mut impl OutputPin, on_ms: u64, off_ms: u64) {
+    led.set_high().ok();
+    Mono::delay(on_ms.millis()).await;
+    led.set_low().ok();
+    Mono::delay(off_ms.millis()).await;
+}
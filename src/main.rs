@@ -3,44 +3,80 @@
 #![feature(type_alias_impl_trait)]
 
 use defmt_rtt as _;
-use panic_rtt_target as _;
 
-// mod config;
-// mod display;
+mod config;
+mod crash;
+mod crc;
+mod diagnostics;
+mod display;
+mod dma_spi;
 mod errors;
+mod fat;
 mod flash;
+#[cfg(feature = "wear-leveling")]
+mod ftl;
+mod geometry;
+mod led;
 mod nvm;
+mod reset;
+mod self_test;
+mod spi_bus;
 mod voltage;
 
 use stm32l0xx_hal as hal;
 
+// Timer driving all async delays (TIM2, clocked at HSI16)
+pub(crate) use rtic_monotonics::stm32::Tim2 as Mono;
+
 #[rtic::app(device = stm32l0xx_hal::pac, dispatchers = [RTC])]
 mod app {
 
     const USB_PACKET_SIZE: u16 = 64; // 8,16,32,64
 
+    // How long the host must leave the write cache alone before we flush it
+    const CACHE_IDLE_TIMEOUT_MS: u64 = 500;
+
+    // How often to look for sectors left to erase by a device erase
+    const ERASE_POLL_MS: u64 = 100;
+
+    // How long the flash must go unused before it is powered down
+    const FLASH_IDLE_TIMEOUT_MS: u64 = 1000;
+
+    // How long USB must be quiet before we start pre-erasing free sectors
+    const PRE_ERASE_IDLE_TIMEOUT_MS: u64 = 5000;
+
     use crate::{
-        // config::{self, FlashConfig, QAType},
-        // display::show_q_or_a,
-        flash::SpiFlash,
+        config::{config_sector, FlashConfig},
+        crash, diagnostics,
+        display::{show_message, show_q_or_a, QAStatus},
+        dma_spi::DmaSpi,
+        errors::{raise, Error},
+        fat::{Fat32, BOOT_SECTOR_SIZE},
+        flash::{SpiFlash, FLASH_SECTOR_SIZE},
         hal::{
+            adc::{Adc, Ready},
             delay::Delay,
             gpio::{
-                gpioa::PA8,
-                gpiob::{PB0, PB1, PB2, PB3, PB4, PB5, PB7, PB8, PB9},
-                OpenDrain, Output, PushPull,
+                gpioa::{PA1, PA4, PA8},
+                gpiob::{PB8, PB9},
+                Analog, OpenDrain, Output, PushPull,
             },
             i2c::I2c,
             pac::I2C1,
             prelude::*,
             rcc::Config,
             signature::device_id,
-            spi::{Spi, MODE_0},
+            spi::MODE_0,
             syscfg::SYSCFG,
             usb::{UsbBus, USB},
         },
-        nvm::Nvm,
-        voltage::VoltageLevels::High,
+        led::{self, LedPattern, LedSender, LED_Q_CAPACITY},
+        nvm::{Nvm, ANSWER_PENDING, CHARGE_LEVEL, CRASH_PENDING, DISPLAY_ADDR},
+        reset::{record_boot, ResetCause},
+        self_test::{self, SelfTestRequest},
+        spi_bus::{ChipSelect, EpdBusy, FlashCsCell, SharedFlash, Spi1, SpiDevices},
+        voltage::{read_charge, VoltageLevels, VoltageLevels::High},
+        Mono,
     };
     use epd_waveshare::{
         epd1in54_v2::{Display1in54, *},
@@ -49,10 +85,9 @@ mod app {
     use hex_display::HexDisplayExt;
     use lps22hb::interface::{i2c::I2cAddress, I2cInterface};
     use lps22hb::*;
+    use rtic_monotonics::stm32::ExtU64;
     use rtic_sync::channel::Receiver;
     use rtic_sync::{channel::*, make_channel};
-    // XXX: This should be replaced by shared_bus_rtic
-    use shared_bus::{BusManager, NullMutex, SpiProxy};
     use shared_bus_rtic::CommonBus;
     use shtcx::{sensor_class::Sht2Gen, shtc3, PowerMode, ShtCx};
     use usb_device::{
@@ -61,45 +96,34 @@ mod app {
     };
     use usbd_scsi::Scsi;
 
-    type BusMgrInner = NullMutex<
-        Spi<
-            stm32l0xx_hal::pac::SPI1,
-            (
-                PB3<stm32l0xx_hal::gpio::Analog>,
-                PB4<stm32l0xx_hal::gpio::Analog>,
-                PB5<stm32l0xx_hal::gpio::Analog>,
-            ),
-        >,
-    >;
-    type BusMgr = BusManager<BusMgrInner>;
-
     #[shared]
-    struct Shared {}
+    struct Shared {
+        // Flash (owned by the SCSI driver) and EPD, see spi_bus.rs
+        spi_devices: SpiDevices,
+    }
 
     #[local]
     struct Local {
+        adc: Adc<Ready>,
         delay: Delay,
-        epd: Epd1in54<
-            SpiProxy<'static, BusMgrInner>,
-            PB2<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-            PB7<stm32l0xx_hal::gpio::Input<stm32l0xx_hal::gpio::Floating>>,
-            PB1<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-            PB0<stm32l0xx_hal::gpio::Output<stm32l0xx_hal::gpio::PushPull>>,
-            stm32l0xx_hal::delay::Delay,
-        >,
+        // Played by led_handler, queued by the others
         led_b: PA8<Output<PushPull>>,
-        scsi: Scsi<'static, UsbBus<USB>, SpiFlash<'static>>,
+        epd_led: LedSender,
+        flusher_led: LedSender,
+        usb_led: LedSender,
         sht: ShtCx<
             Sht2Gen,
             &'static CommonBus<I2c<I2C1, PB9<Output<OpenDrain>>, PB8<Output<OpenDrain>>>>,
         >,
-        spi_epd: SpiProxy<'static, BusMgrInner>,
+        supercap_in: PA1<Analog>,
+        supercap_read_enable: PA4<Output<PushPull>>,
         usb_dev: UsbDevice<'static, UsbBus<USB>>,
     }
 
     const MSG_Q_CAPACITY: usize = 1;
     #[init(local = [USB_BUS: Option<UsbBusAllocator<UsbBus<USB>>> = None,
-                    SPI_BUS: Option<BusMgr> = None])]
+                    FLASH_CS: Option<FlashCsCell> = None,
+                    SECTOR_BUF: [u8; FLASH_SECTOR_SIZE] = [0; FLASH_SECTOR_SIZE]])]
     fn init(cx: init::Context) -> (Shared, Local) {
         let p = cx.device;
         let cp = cx.core;
@@ -107,6 +131,31 @@ mod app {
         let mut syscfg = SYSCFG::new(p.SYSCFG, &mut rcc);
         let hsi48 = rcc.enable_hsi48(&mut syscfg, p.CRS);
         let mut nvm = Nvm::new(p.FLASH, &mut rcc);
+        if let Err(e) = nvm.migrate() {
+            defmt::error!("Failed to migrate settings: {}", Error::from(e));
+        }
+        let reset_cause = ResetCause::take();
+        match record_boot(&mut nvm, reset_cause) {
+            Ok(boot) => defmt::info!("Boot {}, reset by {}", boot, <&str>::from(reset_cause)),
+            Err(e) => defmt::error!("Failed to record boot: {}", Error::from(e)),
+        }
+        // Init failures are retried with a reset, but only once in a row
+        let crash = crash::take();
+        let retry_init = crash.is_none();
+        if let Some(crash) = crash {
+            defmt::warn!("Recovered from a crash");
+            if let Err(e) = nvm
+                .save_crash_record(&crash)
+                .and_then(|_| nvm.set(CRASH_PENDING, true))
+            {
+                defmt::error!("Failed to save crash record: {}", Error::from(e));
+            }
+        }
+
+        let mono_token = rtic_monotonics::create_stm32_tim2_monotonic_token!();
+        Mono::start(16_000_000, mono_token);
+
+        let adc = p.ADC.constrain(&mut rcc);
 
         // gpioa
         let gpioa = p.GPIOA.split(&mut rcc);
@@ -126,6 +175,7 @@ mod app {
 
         let usb = USB::new(p.USB, gpioa.pa11, gpioa.pa12, hsi48);
         let mut delay = Delay::new(cp.SYST, rcc.clocks);
+        let mut led_b = gpioa.pa8.into_push_pull_output();
 
         // trick to make usb_bus live forever, lifted from
         // https://github.com/rtic-rs/rtic-examples/blob/master/rtic_v1/stm32f0_hid_mouse/src/main.rs
@@ -135,12 +185,14 @@ mod app {
         let spi = p
             .SPI1
             .spi((sck, miso, mosi), MODE_0, 4_000_000.Hz(), &mut rcc);
+        let mut scb = cp.SCB;
+        let spi = DmaSpi::new(spi, p.DMA1, &mut scb);
 
-        // Create a shared SPI bus and also make it live forever
-        let spi_bus = cx.local.SPI_BUS;
-        *spi_bus = Some(shared_bus::BusManagerSimple::new(spi));
-        let mut spi_epd = spi_bus.as_ref().unwrap().acquire_spi();
-        let spi_flash = spi_bus.as_ref().unwrap().acquire_spi();
+        // Create a shared SPI bus.  Every device on it ends up in the
+        // `spi_devices` shared resource, which is what serializes access.
+        let spi_bus = shared_bus_rtic::new!(spi, Spi1);
+        let mut spi_epd = spi_bus.acquire();
+        let spi_flash = spi_bus.acquire();
 
         defmt::info!("Setup I2C...");
         // let i2c = p.I2C1.i2c(sda, scl, 100_000.Hz(), &mut rcc);
@@ -162,14 +214,41 @@ mod app {
 
         // Setup EPD
         defmt::info!("Setup EPD...");
-        let mut epd =
-            Epd1in54::new(&mut spi_epd, cs_epd, busy_in, dc, rst, &mut delay, None).unwrap();
-
-        // TODO: flash will take ownership of nvm.  Need to see how to share it.
-        let mut flash = SpiFlash::new(spi_flash, cs_flash, nvm, &mut delay);
-        // let mut config = config::FlashConfig::from_flash(&mut flash);
-
-        let scsi: Scsi<'_, UsbBus<USB>, SpiFlash<'_>> = Scsi::new(
+        let epd = Epd1in54::new(
+            &mut spi_epd,
+            ChipSelect::new(cs_epd),
+            EpdBusy::new(busy_in),
+            dc,
+            rst,
+            &mut delay,
+            None,
+        )
+        .unwrap_or_else(|_| {
+            if retry_init {
+                defmt::panic!("Failed to set up EPD: {}", Error::Display);
+            }
+            defmt::error!("Failed to set up EPD again: {}", Error::Display);
+            led::halt(&mut led_b, Error::Display.code(), &mut delay)
+        });
+
+        // Better not to show up on USB at all than with the wrong size
+        let flash = SpiFlash::new(
+            spi_flash,
+            cs_flash,
+            cx.local.FLASH_CS,
+            nvm,
+            &mut delay,
+            cx.local.SECTOR_BUF,
+        )
+        .unwrap_or_else(|e| {
+            if retry_init {
+                defmt::panic!("Unsupported flash: {}", Error::from(e));
+            }
+            defmt::error!("Unsupported flash again: {}", Error::from(e));
+            led::halt(&mut led_b, e, &mut delay)
+        });
+
+        let scsi: Scsi<'_, UsbBus<USB>, SpiFlash> = Scsi::new(
             usb_bus.as_ref().unwrap(),
             USB_PACKET_SIZE,
             flash,
@@ -186,68 +265,342 @@ mod app {
             .build();
 
         let (s, r) = make_channel!(u32, MSG_Q_CAPACITY);
+        let (led_s, led_r) = make_channel!(LedPattern, LED_Q_CAPACITY);
+        led_handler::spawn(led_r).unwrap();
         epd_handler::spawn(r).unwrap();
+        cache_flusher::spawn().unwrap();
+        erased_map_checker::spawn().unwrap();
+        pre_eraser::spawn().unwrap();
+        flash_sleeper::spawn().unwrap();
+        background_eraser::spawn().unwrap();
 
         (
-            Shared {},
+            Shared {
+                spi_devices: SpiDevices { scsi, epd, spi_epd },
+            },
             Local {
+                adc,
                 delay,
-                epd,
-                led_b: gpioa.pa8.into_push_pull_output(),
-                scsi,
+                led_b,
+                epd_led: led_s.clone(),
+                flusher_led: led_s.clone(),
+                usb_led: led_s,
                 sht,
-                spi_epd,
+                supercap_in: gpioa.pa1.into_analog(),
+                supercap_read_enable: gpioa.pa4.into_push_pull_output(),
                 usb_dev,
             },
         )
     }
 
-    #[task(priority = 1, local = [delay, epd, sht, spi_epd])]
+    // Plays LED patterns one after the other, so that nobody else waits on
+    // LED timing
+    #[task(priority = 1, local = [led_b])]
+    async fn led_handler(
+        cx: led_handler::Context,
+        mut receiver: Receiver<'static, LedPattern, LED_Q_CAPACITY>,
+    ) {
+        loop {
+            // Once an error is latched it is all the LED shows
+            let pattern = match led::latched_error() {
+                Some(error) => LedPattern::Error(error),
+                None => match receiver.recv().await {
+                    Ok(pattern) => pattern,
+                    Err(_) => break,
+                },
+            };
+            led::play(cx.local.led_b, pattern).await;
+        }
+    }
+
+    #[task(priority = 1, local = [delay, epd_led, sht], shared = [spi_devices])]
     async fn epd_handler(
         mut cx: epd_handler::Context,
         mut receiver: Receiver<'static, u32, MSG_Q_CAPACITY>,
     ) {
         defmt::info!("epd_handlerx");
-        let epd = cx.local.epd;
-        let spi_epd = cx.local.spi_epd;
         let delay = cx.local.delay;
-        // let flash = cx.local.flash;
 
+        // Not used for anything yet, so not worth failing the card over
         let sht = cx.local.sht;
-        let temperature = sht
+        if sht
             .measure_temperature(PowerMode::NormalMode, delay)
-            .unwrap();
-
-        // XXX: Until we read it from flash
-        // let config = FlashConfig {
-        //     page_size: 8192,
-        //     num_pages: 100,
-        //     q_type: config::QAType::RawImage,
-        //     a_type: QAType::Text,
-        // };
-        // show_q_or_a(
-        //     epd,
-        //     spi_epd,
-        //     High,
-        //     flash,
-        //     delay,
-        //     &config,
-        //     0x0000_0000,
-        //     false
-        // ).ok();
-        defmt::info!("epd stuff here...");
-        delay.delay_ms(1000u32);
+            .is_err()
+        {
+            defmt::error!("Failed to read temperature: {}", Error::Sensor);
+        }
+
+        let devices = &mut cx.shared.spi_devices;
+        if let Err(e) = show_next(devices, delay).await {
+            raise(e, devices, cx.local.epd_led, delay).await;
+        }
+    }
+
+    // A pending crash report, the self-test or diagnostics when the host asked
+    // for them, or else the next card
+    async fn show_next(
+        devices: &mut impl rtic::Mutex<T = SpiDevices>,
+        delay: &mut Delay,
+    ) -> Result<(), Error> {
+        if devices.with_nvm(|nvm| nvm.get(CRASH_PENDING).unwrap_or(false)) {
+            let shown = crash::show(devices, delay).await;
+            devices.with_nvm(|nvm| nvm.set(CRASH_PENDING, false))?;
+            return shown;
+        }
+        if let Some(request) = SelfTestRequest::from_flash(devices) {
+            if let Err(e) = show_message(devices, delay, "Flash self-test\nrunning...").await {
+                defmt::error!("Failed to show self-test status: {}", e);
+            }
+            let report = self_test::run(devices, request).await;
+            let mut text = [0u8; 48];
+            let text = format_no_std::show(
+                &mut text,
+                format_args!(
+                    "Self-test done\n{} sectors\n{} bad",
+                    report.tested, report.bad
+                ),
+            )
+            .unwrap_or("Self-test done");
+            return show_message(devices, delay, text).await;
+        }
+        if diagnostics::requested(devices) {
+            return diagnostics::report(devices, delay).await;
+        }
+        // Without a deck there is nothing to show but how to get one
+        let config = FlashConfig::from_flash(devices)?;
+        let display_addr = devices.with_nvm(|nvm| nvm.get(DISPLAY_ADDR).unwrap_or(0));
+        let show_answer = devices.with_nvm(|nvm| nvm.get(ANSWER_PENDING).unwrap_or(false));
+        match show_q_or_a(devices, High, delay, &config, display_addr, show_answer).await? {
+            QAStatus::AnswerPending => devices.with_nvm(|nvm| nvm.set(ANSWER_PENDING, true))?,
+            QAStatus::ReadyForNextQuestion => devices.with_nvm(|nvm| {
+                nvm.set(ANSWER_PENDING, false)?;
+                nvm.set(DISPLAY_ADDR, config.next_page_addr(display_addr))
+            })?,
+        }
+        Ok(())
     }
 
-    #[task(binds = USB, priority = 2, local = [led_b, scsi, usb_dev])]
-    fn usb_handler(cx: usb_handler::Context) {
-        let led = cx.local.led_b;
-        led.toggle().ok();
+    // Writes the flash write-back cache out once the host stops touching it,
+    // or right away if the supercap is running low.  Without
+    // `write-back-cache` writes go straight to flash and this finds nothing
+    // to do.
+    #[task(
+        priority = 1,
+        local = [adc, flusher_led, supercap_in, supercap_read_enable],
+        shared = [spi_devices]
+    )]
+    async fn cache_flusher(mut cx: cache_flusher::Context) {
+        // Once per boot, so that the boot log has a reading even if the host
+        // never writes anything
+        let charge = read_charge(
+            cx.local.supercap_read_enable,
+            cx.local.supercap_in,
+            cx.local.adc,
+        )
+        .await;
+        note_charge(&mut cx.shared.spi_devices, cx.local.flusher_led, &charge);
+        loop {
+            Mono::delay(CACHE_IDLE_TIMEOUT_MS.millis()).await;
+            let devices = &mut cx.shared.spi_devices;
+            if !devices.with_flash(|flash| flash.write_cache_dirty()) {
+                continue;
+            }
+            let led = &mut *cx.local.flusher_led;
+            let charge = read_charge(
+                cx.local.supercap_read_enable,
+                cx.local.supercap_in,
+                cx.local.adc,
+            )
+            .await;
+            note_charge(devices, led, &charge);
+            // Without a reading, assume the worst
+            let running_low = charge.map_or(true, |charge| charge <= VoltageLevels::VeryLow);
+            let result = devices.with_flash(|flash| {
+                if !flash.write_cache_idle() && !running_low {
+                    return Ok(false);
+                }
+                flash.flush()?;
+                Ok(flash.take_programmed())
+            });
+            match result {
+                Ok(true) => {
+                    led.try_send(LedPattern::Writing).ok();
+                }
+                Ok(false) => {}
+                Err(e) => defmt::error!("Failed to flush write cache: {}", Error::from(e)),
+            }
+        }
+    }
 
-        let usb_dev = cx.local.usb_dev;
-        usb_dev.poll(&mut [cx.local.scsi]);
+    // Saved for the boot log, in case this is the last we hear, and shown on
+    // the LED if running low
+    fn note_charge(
+        devices: &mut impl rtic::Mutex<T = SpiDevices>,
+        led: &mut LedSender,
+        charge: &Result<VoltageLevels, Error>,
+    ) {
+        match charge {
+            Ok(charge) => {
+                if let Err(e) = devices.with_nvm(|nvm| nvm.set(CHARGE_LEVEL, *charge)) {
+                    defmt::error!("Failed to save charge level: {}", Error::from(e));
+                }
+                if *charge <= VoltageLevels::Low {
+                    led.try_send(LedPattern::LowBattery).ok();
+                }
+            }
+            Err(e) => defmt::error!("Failed to read charge: {}", e),
+        }
     }
 
+    // Walks the whole flash checking the NVM erased sectors map against it, so
+    // that write_block_fast never programs over data.  If the map is not known
+    // to be good (fresh or corrupted EEPROM, flash replaced or written by a
+    // programmer) it is cleared and rebuilt from scratch.
+    #[task(priority = 1, shared = [spi_devices])]
+    async fn erased_map_checker(mut cx: erased_map_checker::Context) {
+        let devices = &mut cx.shared.spi_devices;
+        let rebuild = !devices.with_nvm(|nvm| nvm.read_erased_map_valid());
+        if rebuild {
+            defmt::warn!("Rebuilding erased sectors map");
+            if let Err(e) = devices.with_nvm(|nvm| nvm.save_no_sectors_erased()) {
+                defmt::error!("Failed to clear erased sectors map: {}", Error::from(e));
+                return;
+            }
+        }
+        let num_sectors = devices.with_flash(|flash| flash.num_sectors());
+        for sector in 0..num_sectors {
+            if devices
+                .with_flash(|flash| flash.check_erased_sector(sector, rebuild))
+                .is_err()
+            {
+                defmt::error!("Failed to check sector {}", sector);
+                return;
+            }
+            // Give the other tasks a chance to run
+            Mono::delay(1u64.millis()).await;
+        }
+        if rebuild {
+            if let Err(e) = devices.with_nvm(|nvm| nvm.save_erased_map_valid(true)) {
+                defmt::error!("Failed to validate erased sectors map: {}", Error::from(e));
+            }
+        }
+        defmt::info!("Erased sectors map checked");
+    }
+
+    // Erases sectors that belong to free FAT clusters while USB is idle, so
+    // that copying the next deck hits write_block_fast instead of paying for
+    // an erase per sector.  A pass stops as soon as the host comes back and
+    // is only redone after the host has been active again.
+    #[task(priority = 1, shared = [spi_devices])]
+    async fn pre_eraser(mut cx: pre_eraser::Context) {
+        let devices = &mut cx.shared.spi_devices;
+        let mut done = false;
+        loop {
+            Mono::delay(PRE_ERASE_IDLE_TIMEOUT_MS.millis()).await;
+            if !devices.with_flash(|flash| flash.host_idle()) {
+                done = false;
+                continue;
+            }
+            if done {
+                continue;
+            }
+            let mut boot = [0u8; BOOT_SECTOR_SIZE];
+            let Some(fat) = devices
+                .read(0, &mut boot)
+                .ok()
+                .and_then(|_| Fat32::parse(&boot))
+            else {
+                continue;
+            };
+            done = true;
+            let mut erased = 0;
+            // Cards and the deck config are read by address, not through the
+            // filesystem, so FAT doesn't know they are in use
+            let config_sector = config_sector(devices);
+            let deck_sectors = FlashConfig::from_flash(devices).map_or(0, |config| {
+                config.deck_size().div_ceil(FLASH_SECTOR_SIZE as u32)
+            });
+            let visible_sectors = devices.with_flash(|flash| flash.visible_sectors());
+            for sector in deck_sectors..visible_sectors {
+                if sector == config_sector {
+                    continue;
+                }
+                let result = devices.with_flash(|flash| {
+                    if flash.written_since_boot(sector) || !fat.is_sector_free(flash, sector)? {
+                        return Ok(false);
+                    }
+                    flash.pre_erase_sector(sector)
+                });
+                match result {
+                    Ok(true) => erased += 1,
+                    Ok(false) => {}
+                    Err(_) => {
+                        defmt::error!("Failed to pre-erase sector {}", sector);
+                        break;
+                    }
+                }
+                Mono::delay(1u64.millis()).await;
+                if !devices.with_flash(|flash| flash.host_idle()) {
+                    done = false;
+                    break;
+                }
+            }
+            defmt::info!("Pre-erased {} sectors", erased);
+        }
+    }
+
+    // Carries out device erases (SCSI WRITE SAME over the whole drive) one
+    // sector per lock, so that USB commands keep being served in between
+    #[task(priority = 1, shared = [spi_devices])]
+    async fn background_eraser(mut cx: background_eraser::Context) {
+        let devices = &mut cx.shared.spi_devices;
+        loop {
+            Mono::delay(ERASE_POLL_MS.millis()).await;
+            loop {
+                match devices.with_flash(|flash| flash.erase_pending()) {
+                    Ok(true) => Mono::delay(1u64.millis()).await,
+                    Ok(false) => break,
+                    Err(_) => {
+                        defmt::error!("Failed to erase sector");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Deep power-down saves the flash's standby current while nothing is
+    // reading or writing it.  SpiFlash wakes it up on the next access.
+    #[task(priority = 1, shared = [spi_devices])]
+    async fn flash_sleeper(mut cx: flash_sleeper::Context) {
+        loop {
+            Mono::delay(FLASH_IDLE_TIMEOUT_MS.millis()).await;
+            if cx
+                .shared
+                .spi_devices
+                .with_flash(|flash| flash.power_down_if_idle())
+                .is_err()
+            {
+                defmt::error!("Failed to power down flash");
+            }
+        }
+    }
+
+    #[task(binds = USB, priority = 2, local = [usb_dev, usb_led], shared = [spi_devices])]
+    fn usb_handler(mut cx: usb_handler::Context) {
+        let usb_dev = cx.local.usb_dev;
+        let programmed = cx.shared.spi_devices.lock(|devices| {
+            usb_dev.poll(&mut [&mut devices.scsi]);
+            devices.flash().take_programmed()
+        });
+
+        let pattern = if programmed {
+            LedPattern::Writing
+        } else {
+            LedPattern::UsbActivity
+        };
+        cx.local.usb_led.try_send(pattern).ok();
+    }
     static mut THIS_DEVICE_ID: [u8; 12] = [0u8; 12];
     static mut SERIAL_NUM: [u8; 25] = [0; 25];
 
@@ -1,4 +1,4 @@
-use core::ptr;
+use core::{marker::PhantomData, ptr};
 use int_enum::IntEnum;
 use stm32l0xx_hal::{
     flash::{EEPROM_START_BANK1, EEPROM_START_BANK2, FLASH},
@@ -6,21 +6,136 @@ use stm32l0xx_hal::{
     rcc::Rcc,
 };
 
-use crate::voltage::VoltageLevels;
+use crate::crc::crc32;
+#[cfg(feature = "wear-leveling")]
+use crate::ftl;
+use crate::{
+    crash::{CrashRecord, CRASH_RECORD_WORDS},
+    flash::FLASH_SECTOR_SIZE,
+    reset::BootRecord,
+    voltage::VoltageLevels,
+};
 
-#[derive(Copy, Clone)]
-enum NvmVariableNames {
-    WakeUpReason = 0x4,
-    VoltageLevel = 0x8,
-    DisplayAddress = 0xc,
-    AnswerPending = 0x10,
-}
+// Settings live in a record store in bank 1.  A header word (magic, with the
+// schema version in the low byte) is followed by one fixed size slot per key:
+//
+//   0x0  key id | value length << 8
+//   0x4  value, padded with 0xff
+//   0x1c CRC32 of the two fields above
+//
+// A slot only holds a value if all of it checks out, so blank, torn or
+// foreign words read as "not set".  Slots start past the words the firmware
+// used before the store existed.
+//
+// Bump the schema version whenever a key changes meaning or encoding, and
+// teach `migrate` to convert from the previous one.
+const STORE_SCHEMA_VERSION: u32 = 1;
+const STORE_MAGIC_MASK: u32 = 0xffff_ff00;
+const STORE_MAGIC: u32 = 0x5e77_0000 | STORE_SCHEMA_VERSION;
+const STORE_HEADER: usize = EEPROM_START_BANK1 + 0x40;
+const RECORD_SIZE: usize = 0x20;
+const MAX_VALUE_SIZE: usize = RECORD_SIZE - 8;
+const MAX_KEYS: u8 = 64;
+
+pub(crate) const WAKE_UP_REASON: Key<WakeUpReasons> = Key::new(1);
+pub(crate) const CHARGE_LEVEL: Key<VoltageLevels> = Key::new(2);
+pub(crate) const DISPLAY_ADDR: Key<u32> = Key::new(3);
+pub(crate) const ANSWER_PENDING: Key<bool> = Key::new(4);
+// 5 was the journal target, now logged in the journal sectors themselves
+pub(crate) const BOOT_COUNT: Key<u32> = Key::new(6);
+// A crash record was saved that hasn't been shown on the screen yet
+pub(crate) const CRASH_PENDING: Key<bool> = Key::new(7);
+// Ring of the last boots, indexed by boot count (see reset.rs)
+pub(crate) const BOOT_LOG: [Key<BootRecord>; 8] = [
+    Key::new(8),
+    Key::new(9),
+    Key::new(10),
+    Key::new(11),
+    Key::new(12),
+    Key::new(13),
+    Key::new(14),
+    Key::new(15),
+];
+
+// Last crash, copied out of RAM at boot (see crash.rs).  Past the end of the
+// record store, and checked by its own CRC.
+const CRASH_RECORD: usize = EEPROM_START_BANK1 + 0x900;
+
+// Sectors a device erase still has to get to, one bit each (set = pending,
+// so a blank EEPROM means no erase in progress).  Lets an erase cut short by
+// a reset carry on at the next boot.
+const FLASH_WIPE_SECTORS_MAP: usize = EEPROM_START_BANK1 + 0xa00;
+const _: () = assert!(
+    CRASH_RECORD + 4 * CRASH_RECORD_WORDS <= FLASH_WIPE_SECTORS_MAP
+        && FLASH_WIPE_SECTORS_MAP + MAX_FLASH_SECTORS as usize / 8 <= EEPROM_START_BANK1 + 0xc00
+);
+
+// Schema version 0: one word per setting at a fixed offset in bank 1, with
+// no header.  Only read by `migrate`.
+const LEGACY_WAKE_UP_REASON: usize = EEPROM_START_BANK1 + 0x4;
+const LEGACY_VOLTAGE_LEVEL: usize = EEPROM_START_BANK1 + 0x8;
+const LEGACY_DISPLAY_ADDRESS: usize = EEPROM_START_BANK1 + 0xc;
+const LEGACY_ANSWER_PENDING: usize = EEPROM_START_BANK1 + 0x10;
+
+// The erased sectors map is preceded by a header word that is only valid
+// while the map is known to match the flash.  The low byte is the layout
+// version.
+const FLASH_ERASED_SECTORS_MAP_HEADER: usize = EEPROM_START_BANK2;
+const FLASH_ERASED_SECTORS_MAP_MAGIC: u32 = 0xe5ed_0001;
+const FLASH_ERASED_SECTORS_MAP: usize = FLASH_ERASED_SECTORS_MAP_HEADER + 4;
+
+// Sectors that failed the self-test, one bit each (set = bad, so a blank
+// EEPROM means no bad sectors).  Kept clear of the wear leveling tables
+// whether or not they are in use.
+const FLASH_BAD_SECTORS_MAP: usize = EEPROM_START_BANK2 + 0x800;
+
+// Room for a map entry per hot sector and an erase count per pool sector,
+// set aside even without wear leveling so that the bank 2 layout doesn't
+// depend on the features
+const FTL_TABLES_SIZE: usize = 4 * (128 + 160);
+
+// The erased sectors map gets what is left between the header and the bad
+// sectors map once the wear leveling tables are set aside, the bad sectors
+// map the rest of bank 2.  Whole words only.
+const MAP_SECTORS: u32 = {
+    let erased = (FLASH_BAD_SECTORS_MAP - FLASH_ERASED_SECTORS_MAP - FTL_TABLES_SIZE) * 8;
+    let bad = (EEPROM_END - FLASH_BAD_SECTORS_MAP) * 8;
+    (if erased < bad { erased } else { bad }) as u32 & !31
+};
+
+// The w25q driver sends 3 byte addresses, which reach 16 MiB
+const ADDRESSABLE_SECTORS: u32 = (1 << 24) / FLASH_SECTOR_SIZE as u32;
+
+// Largest flash we can handle.  The actual size is read from the chip at
+// boot.
+pub(crate) const MAX_FLASH_SECTORS: u32 = if MAP_SECTORS < ADDRESSABLE_SECTORS {
+    MAP_SECTORS
+} else {
+    ADDRESSABLE_SECTORS
+};
+
+// Wear leveling tables follow the erased sectors map in bank 2
+#[cfg(feature = "wear-leveling")]
+const FTL_MAP: usize = FLASH_ERASED_SECTORS_MAP + MAX_FLASH_SECTORS as usize / 8;
+#[cfg(feature = "wear-leveling")]
+const FTL_ERASE_COUNTS: usize = FTL_MAP + 4 * ftl::HOT_SECTORS as usize;
+#[cfg(feature = "wear-leveling")]
+const _: () =
+    assert!(4 * ftl::HOT_SECTORS as usize + 4 * ftl::POOL_SECTORS as usize <= FTL_TABLES_SIZE);
 
-const FLASH_NUM_SECTORS: u32 = 4096;
-const FLASH_ERASED_SECTORS_MAP: usize = EEPROM_START_BANK2;
+// Data EEPROM, 3 KiB per bank on the STM32L072
+const EEPROM_END: usize = EEPROM_START_BANK2 + 0xc00;
 
+#[derive(Debug, Clone, Copy)]
 pub enum Error {
+    // Sector, pool or key index past the end of its table
     InvalidAddress,
+    // EEPROM address outside both banks
+    OutOfRange,
+    // Word write to an address that isn't a multiple of 4
+    Alignment,
+    // The EEPROM controller reported an error (e.g. supply too low)
+    Programming,
 }
 
 #[repr(u32)]
@@ -35,6 +150,110 @@ pub enum WakeUpReasons {
     SomeOtherWeirdEvent = 6,
 }
 
+// Identifies a setting and the type it holds.  Ids pick the slot, so they
+// must never be reused for something else.
+pub(crate) struct Key<T> {
+    id: u8,
+    value: PhantomData<T>,
+}
+
+impl<T: NvmValue> Key<T> {
+    pub(crate) const fn new(id: u8) -> Self {
+        assert!(id > 0 && id < MAX_KEYS);
+        assert!(T::SIZE <= MAX_VALUE_SIZE);
+        Self {
+            id,
+            value: PhantomData,
+        }
+    }
+
+    fn slot(&self) -> usize {
+        STORE_HEADER + RECORD_SIZE * self.id as usize
+    }
+
+    fn head(&self) -> u32 {
+        self.id as u32 | (T::SIZE as u32) << 8
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Key<T> {}
+
+// Something that can be kept in the record store
+pub(crate) trait NvmValue: Sized {
+    // Encoded size in bytes, at most MAX_VALUE_SIZE
+    const SIZE: usize;
+    fn encode(&self, buf: &mut [u8]);
+    // None if the bytes don't make a valid value
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+impl NvmValue for u32 {
+    const SIZE: usize = 4;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(u32::from_le_bytes(buf.try_into().ok()?))
+    }
+}
+
+impl NvmValue for bool {
+    const SIZE: usize = 1;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = *self as u8;
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        match buf[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! int_enum_value {
+    ($($t:ty),*) => {
+        $(impl NvmValue for $t {
+            const SIZE: usize = 4;
+
+            fn encode(&self, buf: &mut [u8]) {
+                self.int_value().encode(buf)
+            }
+
+            fn decode(buf: &[u8]) -> Option<Self> {
+                Self::from_int(u32::decode(buf)?).ok()
+            }
+        })*
+    };
+}
+
+int_enum_value!(WakeUpReasons, VoltageLevels);
+
+fn record_crc(head: u32, value: &[u8]) -> u32 {
+    let mut buf = [0u8; 4 + MAX_VALUE_SIZE];
+    buf[..4].copy_from_slice(&head.to_le_bytes());
+    buf[4..4 + value.len()].copy_from_slice(value);
+    crc32(&buf[..4 + value.len()])
+}
+
+fn read_word(address: usize) -> u32 {
+    unsafe { ptr::read_volatile(address as *const u32) }
+}
+
+fn read_byte(address: usize) -> u8 {
+    unsafe { ptr::read_volatile(address as *const u8) }
+}
+
 pub struct Nvm {
     nvm: FLASH,
 }
@@ -45,70 +264,173 @@ impl Nvm {
         Self { nvm }
     }
 
-    pub(crate) fn save_wakeup_reason(self: &mut Self, reason: WakeUpReasons) {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::WakeUpReason as usize) as *mut u32;
-        self.nvm
-            .write_word(address, reason as u32)
-            .expect("Failed to write to EEPROM");
+    pub(crate) fn get<T: NvmValue>(self: &Self, key: Key<T>) -> Option<T> {
+        if read_word(STORE_HEADER) != STORE_MAGIC {
+            return None;
+        }
+        let slot = key.slot();
+        if read_word(slot) != key.head() {
+            return None;
+        }
+        let mut value = [0u8; MAX_VALUE_SIZE];
+        for (i, word) in value.chunks_mut(4).enumerate() {
+            word.copy_from_slice(&read_word(slot + 4 + 4 * i).to_le_bytes());
+        }
+        let value = &value[..T::SIZE];
+        if read_word(slot + RECORD_SIZE - 4) != record_crc(key.head(), value) {
+            return None;
+        }
+        T::decode(value)
     }
 
-    pub(crate) fn read_wakeup_reason(self: &Self) -> WakeUpReasons {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::WakeUpReason as usize) as *mut u32;
-        let val = unsafe { *address };
-        let reason = WakeUpReasons::from_int(val);
-        if reason.is_err() {
-            WakeUpReasons::SomeOtherWeirdEvent
-        } else {
-            reason.unwrap()
+    pub(crate) fn set<T: NvmValue>(self: &mut Self, key: Key<T>, value: T) -> Result<(), Error> {
+        if read_word(STORE_HEADER) != STORE_MAGIC {
+            self.format_store()?;
         }
+        self.write_record(key, value)
     }
 
-    pub(crate) fn save_charge_level(self: &mut Self, charge: VoltageLevels) {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::VoltageLevel as usize) as *mut u32;
-        self.nvm
-            .write_word(address, charge as u32)
-            .expect("Failed to write to EEPROM");
+    fn write_record<T: NvmValue>(self: &mut Self, key: Key<T>, value: T) -> Result<(), Error> {
+        let mut buf = [0xffu8; MAX_VALUE_SIZE];
+        value.encode(&mut buf[..T::SIZE]);
+        let slot = key.slot();
+        self.write_word(slot, key.head())?;
+        for (i, word) in buf.chunks(4).enumerate() {
+            self.write_word(
+                slot + 4 + 4 * i,
+                u32::from_le_bytes(word.try_into().unwrap()),
+            )?;
+        }
+        self.write_word(
+            slot + RECORD_SIZE - 4,
+            record_crc(key.head(), &buf[..T::SIZE]),
+        )
     }
 
-    pub(crate) fn read_charge_level(self: &Self) -> VoltageLevels {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::VoltageLevel as usize) as *mut u32;
-        let val = unsafe { *address };
-        let reason = VoltageLevels::from_int(val);
-        if reason.is_err() {
-            VoltageLevels::Critical
-        } else {
-            reason.unwrap()
+    // Drop every record, then claim the store for this schema version
+    fn format_store(self: &mut Self) -> Result<(), Error> {
+        defmt::warn!("Formatting settings store");
+        self.clear_records()?;
+        self.write_word(STORE_HEADER, STORE_MAGIC)
+    }
+
+    fn clear_records(self: &mut Self) -> Result<(), Error> {
+        for id in 1..MAX_KEYS {
+            self.write_word(STORE_HEADER + RECORD_SIZE * id as usize, 0)?;
         }
+        Ok(())
     }
 
-    pub(crate) fn save_display_addr(self: &mut Self, disp_addr: u32) {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::DisplayAddress as usize) as *mut u32;
-        self.nvm
-            .write_word(address, disp_addr)
-            .expect("Failed to write to EEPROM");
+    // Bring the settings written by an older firmware up to the current
+    // schema.  Runs at boot, before anything reads them.  The header is only
+    // written once every record is converted, so a reset half way starts the
+    // migration over.
+    pub(crate) fn migrate(self: &mut Self) -> Result<(), Error> {
+        let header = read_word(STORE_HEADER);
+        if header == STORE_MAGIC {
+            return Ok(());
+        }
+        if header & STORE_MAGIC_MASK == STORE_MAGIC & STORE_MAGIC_MASK {
+            // Left by a newer firmware: nothing we can make sense of
+            defmt::warn!(
+                "Unknown settings schema version {}",
+                header & !STORE_MAGIC_MASK
+            );
+            return self.format_store();
+        }
+        defmt::info!("Migrating settings from schema version 0");
+        self.clear_records()?;
+        self.migrate_value(
+            "wake up reason",
+            WAKE_UP_REASON,
+            WakeUpReasons::from_int(read_word(LEGACY_WAKE_UP_REASON)).ok(),
+        )?;
+        self.migrate_value(
+            "charge level",
+            CHARGE_LEVEL,
+            VoltageLevels::from_int(read_word(LEGACY_VOLTAGE_LEVEL)).ok(),
+        )?;
+        let display_addr = read_word(LEGACY_DISPLAY_ADDRESS);
+        self.migrate_value(
+            "display address",
+            DISPLAY_ADDR,
+            Some(display_addr).filter(|&addr| addr != 0xffff_ffff),
+        )?;
+        // Used to be read as "any non-zero value", which includes garbage
+        self.migrate_value(
+            "answer pending",
+            ANSWER_PENDING,
+            match read_word(LEGACY_ANSWER_PENDING) {
+                0 => Some(false),
+                1 => Some(true),
+                _ => None,
+            },
+        )?;
+        self.write_word(STORE_HEADER, STORE_MAGIC)?;
+        defmt::info!("Settings now at schema version {}", STORE_SCHEMA_VERSION);
+        Ok(())
     }
 
-    pub(crate) fn read_disp_addr(self: &Self) -> Option<u32> {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::DisplayAddress as usize) as *mut u32;
-        let val = unsafe { *address };
-        if val != 0xffff_ffff {
-            Some(val)
-        } else {
-            None
+    fn migrate_value<T: NvmValue>(
+        self: &mut Self,
+        name: &str,
+        key: Key<T>,
+        value: Option<T>,
+    ) -> Result<(), Error> {
+        match value {
+            Some(value) => {
+                defmt::info!("Migrated {}", name);
+                self.write_record(key, value)
+            }
+            None => {
+                defmt::warn!("Reset {}, stored value is invalid", name);
+                Ok(())
+            }
         }
     }
 
-    pub(crate) fn save_answer_pending(self: &mut Self, answer_pending: bool) {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::AnswerPending as usize) as *mut u32;
-        self.nvm
-            .write_word(address, answer_pending as u32)
-            .expect("Failed to write to EEPROM");
+    // EEPROM words wear out, so leave alone those that already hold `val`
+    fn write_word(self: &mut Self, address: usize, val: u32) -> Result<(), Error> {
+        if address < EEPROM_START_BANK1 || address + 4 > EEPROM_END {
+            return Err(Error::OutOfRange);
+        }
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+        if read_word(address) != val {
+            self.nvm
+                .write_word(address as *mut u32, val)
+                .map_err(|_| Error::Programming)?;
+        }
+        Ok(())
     }
 
-    pub(crate) fn read_answer_pending(self: &Self) -> bool {
-        let address = (EEPROM_START_BANK1 + NvmVariableNames::AnswerPending as usize) as *mut u32;
-        let val = unsafe { *address };
-        val != 0
+    fn write_byte(self: &mut Self, address: usize, val: u8) -> Result<(), Error> {
+        if address < EEPROM_START_BANK1 || address >= EEPROM_END {
+            return Err(Error::OutOfRange);
+        }
+        if read_byte(address) != val {
+            self.nvm
+                .write_byte(address as *mut u8, val)
+                .map_err(|_| Error::Programming)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_crash_record(self: &mut Self, record: &CrashRecord) -> Result<(), Error> {
+        for (i, word) in record.as_words().iter().enumerate() {
+            self.write_word(CRASH_RECORD + 4 * i, *word)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_crash_record(self: &Self) -> Option<CrashRecord> {
+        let mut words = [0u32; CRASH_RECORD_WORDS];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = read_word(CRASH_RECORD + 4 * i);
+        }
+        let record = CrashRecord::from_words(words);
+        record.is_valid().then_some(record)
     }
 
     pub(crate) fn read_raw(
@@ -119,7 +441,7 @@ impl Nvm {
     ) -> Result<(), Error> {
         const EEPROM_SIZE_IN_BYTES: u32 = 2_048;
         if offset + length as u32 > EEPROM_SIZE_IN_BYTES {
-            return Err(Error::InvalidAddress);
+            return Err(Error::OutOfRange);
         }
         let address = (EEPROM_START_BANK1 as u32 + offset) as *mut u8;
         unsafe {
@@ -130,7 +452,7 @@ impl Nvm {
     }
 
     pub(crate) fn read_sector_is_erased(self: &Self, sector: u32) -> Result<bool, Error> {
-        if sector > FLASH_NUM_SECTORS {
+        if sector >= MAX_FLASH_SECTORS {
             return Err(Error::InvalidAddress);
         }
         let address = (FLASH_ERASED_SECTORS_MAP + sector as usize / 8) as *mut u8;
@@ -147,16 +469,83 @@ impl Nvm {
         Ok(is_set)
     }
 
+    pub(crate) fn read_sector_is_bad(self: &Self, sector: u32) -> Result<bool, Error> {
+        if sector >= MAX_FLASH_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        let address = (FLASH_BAD_SECTORS_MAP + sector as usize / 8) as *mut u8;
+        let val = unsafe { *address };
+        Ok(val & (1 << (sector % 8)) != 0)
+    }
+
+    pub(crate) fn save_sector_is_bad(
+        self: &mut Self,
+        sector: u32,
+        is_bad: bool,
+    ) -> Result<(), Error> {
+        if sector >= MAX_FLASH_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        let address = FLASH_BAD_SECTORS_MAP + sector as usize / 8;
+        let val = read_byte(address);
+        let new_val = if is_bad {
+            val | (1 << (sector % 8))
+        } else {
+            val & !(1 << (sector % 8))
+        };
+        self.write_byte(address, new_val)
+    }
+
+    // Fill `map` (one bit per sector, like the EEPROM copy) with the
+    // sectors a device erase still has to get to
+    pub(crate) fn read_wipe_map(self: &Self, map: &mut [u32]) -> Result<(), Error> {
+        if map.len() * 32 > MAX_FLASH_SECTORS as usize {
+            return Err(Error::InvalidAddress);
+        }
+        for (i, word) in map.iter_mut().enumerate() {
+            *word = read_word(FLASH_WIPE_SECTORS_MAP + 4 * i);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_wipe_map(self: &mut Self, map: &[u32]) -> Result<(), Error> {
+        if map.len() * 32 > MAX_FLASH_SECTORS as usize {
+            return Err(Error::InvalidAddress);
+        }
+        for (i, word) in map.iter().enumerate() {
+            self.write_word(FLASH_WIPE_SECTORS_MAP + 4 * i, *word)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_sector_wipe_pending(
+        self: &mut Self,
+        sector: u32,
+        pending: bool,
+    ) -> Result<(), Error> {
+        if sector >= MAX_FLASH_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        let address = FLASH_WIPE_SECTORS_MAP + sector as usize / 8;
+        let val = read_byte(address);
+        let new_val = if pending {
+            val | (1 << (sector % 8))
+        } else {
+            val & !(1 << (sector % 8))
+        };
+        self.write_byte(address, new_val)
+    }
+
     pub(crate) fn save_sector_is_erased(
         self: &mut Self,
         sector: u32,
         is_erased: bool,
     ) -> Result<(), Error> {
-        if sector > FLASH_NUM_SECTORS {
+        if sector >= MAX_FLASH_SECTORS {
             return Err(Error::InvalidAddress);
         }
-        let address = (FLASH_ERASED_SECTORS_MAP + sector as usize / 8) as *mut u8;
-        let val = unsafe { *address };
+        let address = FLASH_ERASED_SECTORS_MAP + sector as usize / 8;
+        let val = read_byte(address);
         let bit = sector % 8;
         let new_val;
         if is_erased {
@@ -171,21 +560,79 @@ impl Nvm {
         //     val,
         //     new_val
         // );
-        self.nvm
-            .write_byte(address, new_val)
-            .expect("Failed to write to EEPROM");
-        Ok(())
+        self.write_byte(address, new_val)
+    }
+
+    // Start over from "nothing is erased", which is always safe to assume
+    pub(crate) fn save_no_sectors_erased(self: &mut Self) -> Result<(), Error> {
+        self.save_erased_map_valid(false)?;
+        self.fill_erased_map(0)
     }
 
-    pub(crate) fn save_all_sectors_erased(self: &mut Self) -> Result<(), Error> {
-        let address = FLASH_ERASED_SECTORS_MAP as *mut u32;
-        for i in 0..FLASH_NUM_SECTORS / (4 * 8) {
-            let address = address.wrapping_add(i as usize);
+    fn fill_erased_map(self: &mut Self, val: u32) -> Result<(), Error> {
+        for i in 0..MAX_FLASH_SECTORS as usize / (4 * 8) {
+            let address = FLASH_ERASED_SECTORS_MAP + 4 * i;
             // defmt::info!("erase sectors at map addr 0x{:X}", address);
-            self.nvm
-                .write_word(address, 0xffff_ffff)
-                .expect("Failed to write to EEPROM");
+            self.write_word(address, val)?;
         }
         Ok(())
     }
+
+    pub(crate) fn read_erased_map_valid(self: &Self) -> bool {
+        let address = FLASH_ERASED_SECTORS_MAP_HEADER as *mut u32;
+        let val = unsafe { *address };
+        val == FLASH_ERASED_SECTORS_MAP_MAGIC
+    }
+
+    pub(crate) fn save_erased_map_valid(self: &mut Self, valid: bool) -> Result<(), Error> {
+        let val = if valid {
+            FLASH_ERASED_SECTORS_MAP_MAGIC
+        } else {
+            0
+        };
+        self.write_word(FLASH_ERASED_SECTORS_MAP_HEADER, val)
+    }
+
+    // Pool sector holding hot logical `sector`, if it was ever moved
+    #[cfg(feature = "wear-leveling")]
+    pub(crate) fn read_ftl_map(self: &Self, sector: u32) -> Result<Option<u32>, Error> {
+        if sector >= ftl::HOT_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        let address = (FTL_MAP + 4 * sector as usize) as *mut u32;
+        let val = unsafe { *address };
+        // Entries carry their own complement in the upper half, so that a
+        // blank or torn word is never mistaken for a mapping
+        if val as u16 == !(val >> 16) as u16 {
+            Ok(Some(val & 0xffff))
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[cfg(feature = "wear-leveling")]
+    pub(crate) fn save_ftl_map(self: &mut Self, sector: u32, pool: u32) -> Result<(), Error> {
+        if sector >= ftl::HOT_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        let address = FTL_MAP + 4 * sector as usize;
+        self.write_word(address, (pool & 0xffff) | (!pool << 16))
+    }
+
+    #[cfg(feature = "wear-leveling")]
+    pub(crate) fn read_erase_count(self: &Self, pool: u32) -> u32 {
+        if pool >= ftl::POOL_SECTORS {
+            return u32::MAX;
+        }
+        let address = (FTL_ERASE_COUNTS + 4 * pool as usize) as *mut u32;
+        unsafe { *address }
+    }
+
+    #[cfg(feature = "wear-leveling")]
+    pub(crate) fn save_erase_count(self: &mut Self, pool: u32, count: u32) -> Result<(), Error> {
+        if pool >= ftl::POOL_SECTORS {
+            return Err(Error::InvalidAddress);
+        }
+        self.write_word(FTL_ERASE_COUNTS + 4 * pool as usize, count)
+    }
 }
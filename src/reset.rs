@@ -0,0 +1,133 @@
+// Boot counter and a short history of why the MCU came out of reset, kept in
+// the EEPROM settings store so that a unit stuck in a brown-out or watchdog
+// loop can tell us about it afterwards.
+
+use int_enum::IntEnum;
+use stm32l0xx_hal::pac;
+
+use crate::{
+    nvm::{self, Nvm, NvmValue, WakeUpReasons, BOOT_COUNT, BOOT_LOG, CHARGE_LEVEL, WAKE_UP_REASON},
+    voltage::VoltageLevels,
+};
+
+// RCC_CSR reset flags
+const LPWRRSTF: u32 = 1 << 31;
+const WWDGRSTF: u32 = 1 << 30;
+const IWDGRSTF: u32 = 1 << 29;
+const SFTRSTF: u32 = 1 << 28;
+const PORRSTF: u32 = 1 << 27;
+const PINRSTF: u32 = 1 << 26;
+const OBLRSTF: u32 = 1 << 25;
+const FWRSTF: u32 = 1 << 24;
+const RMVF: u32 = 1 << 23;
+
+#[repr(u32)]
+#[derive(PartialEq, Debug, Clone, Copy, IntEnum)]
+pub(crate) enum ResetCause {
+    // Power on, power down or brown-out: the L0 has a single flag for all of
+    // them
+    PowerOn = 1,
+    Pin = 2,
+    Software = 3,
+    IndependentWatchdog = 4,
+    WindowWatchdog = 5,
+    LowPower = 6,
+    OptionBytes = 7,
+    Firewall = 8,
+    Unknown = 9,
+}
+
+impl From<ResetCause> for &str {
+    fn from(cause: ResetCause) -> Self {
+        match cause {
+            ResetCause::PowerOn => "POR/BOR",
+            ResetCause::Pin => "Pin",
+            ResetCause::Software => "Software",
+            ResetCause::IndependentWatchdog => "IWDG",
+            ResetCause::WindowWatchdog => "WWDG",
+            ResetCause::LowPower => "Low power",
+            ResetCause::OptionBytes => "Option bytes",
+            ResetCause::Firewall => "Firewall",
+            ResetCause::Unknown => "Unknown",
+        }
+    }
+}
+
+impl ResetCause {
+    // Read the reset flags and clear them for the next boot.  Every internal
+    // reset also pulls NRST low, so PINRSTF only counts when nothing else is
+    // set.
+    pub(crate) fn take() -> Self {
+        let rcc = unsafe { &*pac::RCC::ptr() };
+        let csr = rcc.csr.read().bits();
+        rcc.csr.modify(|r, w| unsafe { w.bits(r.bits() | RMVF) });
+        [
+            (IWDGRSTF, ResetCause::IndependentWatchdog),
+            (WWDGRSTF, ResetCause::WindowWatchdog),
+            (SFTRSTF, ResetCause::Software),
+            (LPWRRSTF, ResetCause::LowPower),
+            (FWRSTF, ResetCause::Firewall),
+            (OBLRSTF, ResetCause::OptionBytes),
+            (PORRSTF, ResetCause::PowerOn),
+            (PINRSTF, ResetCause::Pin),
+        ]
+        .into_iter()
+        .find(|(flag, _)| csr & flag != 0)
+        .map_or(ResetCause::Unknown, |(_, cause)| cause)
+    }
+}
+
+// One boot, with the wake up reason and charge level last saved before it
+#[derive(Clone, Copy)]
+pub(crate) struct BootRecord {
+    pub(crate) boot: u32,
+    pub(crate) cause: ResetCause,
+    pub(crate) wake_up: Option<WakeUpReasons>,
+    pub(crate) charge: Option<VoltageLevels>,
+}
+
+// Encoded as the boot number, then one byte each for the cause, wake up
+// reason and charge level (0xff when unknown)
+impl NvmValue for BootRecord {
+    const SIZE: usize = 7;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.boot.to_le_bytes());
+        buf[4] = self.cause as u8;
+        buf[5] = self.wake_up.map_or(0xff, |reason| reason as u8);
+        buf[6] = self.charge.map_or(0xff, |charge| charge as u8);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(Self {
+            boot: u32::decode(&buf[..4])?,
+            cause: ResetCause::from_int(buf[4] as u32).ok()?,
+            wake_up: WakeUpReasons::from_int(buf[5] as u32).ok(),
+            charge: VoltageLevels::from_int(buf[6] as u32).ok(),
+        })
+    }
+}
+
+// Count this boot and log why it happened
+pub(crate) fn record_boot(nvm: &mut Nvm, cause: ResetCause) -> Result<u32, nvm::Error> {
+    let boot = nvm.get(BOOT_COUNT).unwrap_or(0).wrapping_add(1);
+    let record = BootRecord {
+        boot,
+        cause,
+        wake_up: nvm.get(WAKE_UP_REASON),
+        charge: nvm.get(CHARGE_LEVEL),
+    };
+    nvm.set(BOOT_LOG[boot as usize % BOOT_LOG.len()], record)?;
+    nvm.set(BOOT_COUNT, boot)?;
+    Ok(boot)
+}
+
+// The logged boots, newest first
+pub(crate) fn boot_history(nvm: &Nvm) -> impl Iterator<Item = BootRecord> + '_ {
+    let last = nvm.get(BOOT_COUNT).unwrap_or(0);
+    (0..BOOT_LOG.len() as u32).filter_map(move |age| {
+        let boot = last.checked_sub(age)?;
+        nvm.get(BOOT_LOG[boot as usize % BOOT_LOG.len()])
+            .filter(|record| record.boot == boot)
+    })
+}
@@ -0,0 +1,129 @@
+// Flash self-test, requested and reported through the deck config sector.
+//
+// The host starts a test by writing a request in place of the deck config
+// (see scripts/self_test.sh):
+//
+//   0x0  SELF_TEST_REQUEST_MAGIC
+//   0x4  first sector to test
+//   0x8  number of sectors (0: up to the last one the host sees)
+//
+// The range is cut down to `SpiFlash::testable_sectors`, which leaves out the
+// hidden sectors at the end of the flash and, with wear leveling, the hot
+// sectors at the start.  At the next boot every sector in it goes through
+// `SpiFlash::test_sector`, which destroys its contents and records it in the
+// NVM bad sector map.  The config sector is then overwritten with the report:
+//
+//   0x0  SELF_TEST_REPORT_MAGIC
+//   0x4  first sector tested
+//   0x8  number of sectors tested
+//   0xc  number of bad sectors found
+//   0x10 the bad sectors, one u32 each (as many as fit)
+
+use core::convert::TryInto;
+
+use rtic::Mutex;
+use rtic_monotonics::stm32::ExtU64;
+
+use crate::{
+    config::config_sector,
+    flash::FLASH_SECTOR_SIZE,
+    spi_bus::{SharedFlash, SpiDevices},
+    Mono,
+};
+
+const SELF_TEST_REQUEST_MAGIC: u32 = 0x5e1f_7e57;
+const SELF_TEST_REPORT_MAGIC: u32 = 0x5e1f_7e58;
+const REPORT_HEADER_SIZE: usize = 0x10;
+
+pub(crate) struct SelfTestRequest {
+    first: u32,
+    count: u32,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct SelfTestReport {
+    pub(crate) tested: u32,
+    pub(crate) bad: u32,
+}
+
+impl SelfTestRequest {
+    pub(crate) fn from_flash(flash: &mut impl SharedFlash) -> Option<Self> {
+        let addr = config_sector(flash) * FLASH_SECTOR_SIZE as u32;
+        let mut buf = [0u8; 12];
+        flash.read(addr, &mut buf).ok()?;
+        let word = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        if word(0) != SELF_TEST_REQUEST_MAGIC {
+            return None;
+        }
+        Some(Self {
+            first: word(4),
+            count: word(8),
+        })
+    }
+}
+
+pub(crate) async fn run(
+    devices: &mut impl Mutex<T = SpiDevices>,
+    request: SelfTestRequest,
+) -> SelfTestReport {
+    let testable = devices.with_flash(|flash| flash.testable_sectors());
+    let first = request.first.clamp(testable.start, testable.end);
+    let end = match request.count {
+        0 => testable.end,
+        count => request
+            .first
+            .saturating_add(count)
+            .clamp(first, testable.end),
+    };
+    defmt::info!("Self-test of sectors {}..{}", first, end);
+
+    // Clear the request first so that a reset half way doesn't start over
+    let config_sector = config_sector(devices);
+    if devices
+        .with_flash(|flash| flash.rewrite_sector(config_sector, |_, _| {}))
+        .is_err()
+    {
+        defmt::error!("Failed to clear self-test request");
+    }
+
+    let mut report = SelfTestReport { tested: 0, bad: 0 };
+    for sector in first..end {
+        match devices.with_flash(|flash| flash.test_sector(sector)) {
+            Ok(true) => {}
+            Ok(false) => report.bad += 1,
+            Err(_) => {
+                defmt::error!("Self-test stopped at sector {}", sector);
+                break;
+            }
+        }
+        report.tested += 1;
+        // Give USB a chance in between
+        Mono::delay(1u64.millis()).await;
+    }
+
+    let SelfTestReport { tested, bad } = report;
+    let result = devices.with_flash(|flash| {
+        flash.rewrite_sector(config_sector, |buf, nvm| {
+            let mut offset = REPORT_HEADER_SIZE;
+            for sector in first..first + tested {
+                if nvm.read_sector_is_bad(sector).unwrap_or(false) && offset + 4 <= buf.len() {
+                    buf[offset..offset + 4].copy_from_slice(&sector.to_le_bytes());
+                    offset += 4;
+                }
+            }
+            buf[0x0..0x4].copy_from_slice(&SELF_TEST_REPORT_MAGIC.to_le_bytes());
+            buf[0x4..0x8].copy_from_slice(&first.to_le_bytes());
+            buf[0x8..0xc].copy_from_slice(&tested.to_le_bytes());
+            buf[0xc..0x10].copy_from_slice(&bad.to_le_bytes());
+        })
+    });
+    if result.is_err() {
+        defmt::error!("Failed to write self-test report");
+    }
+    defmt::info!(
+        "Self-test done, {} of {} sectors bad",
+        report.bad,
+        report.tested
+    );
+    report
+}
@@ -0,0 +1,209 @@
+use core::{
+    cell::RefCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use epd_waveshare::epd1in54_v2::Epd1in54;
+use rtic::Mutex;
+use shared_bus_rtic::CommonBus;
+use stm32l0xx_hal::{
+    delay::Delay,
+    gpio::{
+        gpiob::{PB0, PB1, PB2, PB6, PB7},
+        Floating, Input, Output, PushPull,
+    },
+    pac::GPIOB,
+    prelude::{InputPin, OutputPin},
+    usb::{UsbBus, USB},
+};
+use usbd_scsi::{BlockDeviceError, Scsi};
+
+use crate::{dma_spi::DmaSpi, flash::SpiFlash, nvm::Nvm};
+
+pub(crate) type Spi1 = DmaSpi;
+
+// Handle to SPI1 given to each driver.  shared_bus_rtic panics if two of them
+// ever use the bus at the same time, which can only happen if a driver is used
+// outside of a `SpiDevices` lock.
+pub(crate) type SpiProxy = &'static CommonBus<Spi1>;
+
+pub(crate) type Epd = Epd1in54<
+    SpiProxy,
+    ChipSelect<PB2<Output<PushPull>>>,
+    EpdBusy<PB7<Input<Floating>>>,
+    PB1<Output<PushPull>>,
+    PB0<Output<PushPull>>,
+    Delay,
+>;
+
+// Everything hanging off SPI1: the flash (inside the SCSI driver) and the
+// e-paper.  They live in a single RTIC shared resource so that the lock, not
+// luck, keeps a flash transaction from `usb_handler` from landing in the
+// middle of an EPD transaction from `epd_handler`.
+pub(crate) struct SpiDevices {
+    pub(crate) scsi: Scsi<'static, UsbBus<USB>, SpiFlash>,
+    pub(crate) epd: Epd,
+    pub(crate) spi_epd: SpiProxy,
+}
+
+impl SpiDevices {
+    // The SCSI driver owns the flash; this is the one place that reaches in.
+    pub(crate) fn flash(&mut self) -> &mut SpiFlash {
+        self.scsi.block_device_mut()
+    }
+
+    pub(crate) fn nvm(&mut self) -> &mut Nvm {
+        self.flash().nvm_mut()
+    }
+}
+
+// Flash and NVM access for tasks other than `usb_handler`.  Every call takes
+// the `spi_devices` lock just for its own transfer, so USB keeps being served
+// between reads (e.g. while the display is rendering a card).
+pub(crate) trait SharedFlash {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BlockDeviceError>;
+    fn with_flash<R>(&mut self, f: impl FnOnce(&mut SpiFlash) -> R) -> R;
+    fn with_nvm<R>(&mut self, f: impl FnOnce(&mut Nvm) -> R) -> R;
+}
+
+impl<M: Mutex<T = SpiDevices>> SharedFlash for M {
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.lock(|devices| devices.flash().read(addr, buf))
+    }
+
+    fn with_flash<R>(&mut self, f: impl FnOnce(&mut SpiFlash) -> R) -> R {
+        self.lock(|devices| f(devices.flash()))
+    }
+
+    fn with_nvm<R>(&mut self, f: impl FnOnce(&mut Nvm) -> R) -> R {
+        self.lock(|devices| f(devices.nvm()))
+    }
+}
+
+// Set while any chip select on SPI1 is asserted
+static BUS_SELECTED: AtomicBool = AtomicBool::new(false);
+
+// Chip select for a device on SPI1 (PB6 for flash, PB2 for EPD).  Drivers
+// assert it at the start of a transaction and release it at the end; asserting
+// a second chip select before the first one was released means two
+// transactions got interleaved, so we refuse to select the device rather than
+// corrupt flash contents.
+pub(crate) struct ChipSelect<PIN> {
+    pin: PIN,
+    // Whether BUS_SELECTED is ours to release
+    selected: bool,
+}
+
+#[derive(Debug)]
+pub(crate) enum ChipSelectError<E> {
+    Interleaved,
+    Pin(E),
+}
+
+impl<PIN: OutputPin> ChipSelect<PIN> {
+    pub(crate) fn new(mut pin: PIN) -> Self {
+        pin.set_high().ok();
+        Self {
+            pin,
+            selected: false,
+        }
+    }
+}
+
+impl<PIN: OutputPin> OutputPin for ChipSelect<PIN> {
+    type Error = ChipSelectError<PIN::Error>;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        // thumbv6 has no atomic swap, so check and set with interrupts off
+        let busy = !self.selected
+            && cortex_m::interrupt::free(|_| {
+                let busy = BUS_SELECTED.load(Ordering::Relaxed);
+                if !busy {
+                    BUS_SELECTED.store(true, Ordering::Relaxed);
+                }
+                busy
+            });
+        if busy {
+            defmt::error!("SPI1 transactions interleaved");
+            return Err(ChipSelectError::Interleaved);
+        }
+        self.selected = true;
+        self.pin.set_low().map_err(ChipSelectError::Pin)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.pin.set_high().map_err(ChipSelectError::Pin)?;
+        if self.selected {
+            self.selected = false;
+            BUS_SELECTED.store(false, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+// Set while `refresh` wants the EPD driver to return without waiting for the
+// panel
+static EPD_WAIT_DEFERRED: AtomicBool = AtomicBool::new(false);
+
+// The EPD BUSY line (PB7).  The driver busy-waits on it for the whole panel
+// refresh, seconds with the `spi_devices` lock held, so while the wait is
+// deferred it reads as idle and `refresh` polls the real line (`epd_busy`)
+// between locks instead.
+pub(crate) struct EpdBusy<PIN>(PIN);
+
+impl<PIN: InputPin> EpdBusy<PIN> {
+    pub(crate) fn new(pin: PIN) -> Self {
+        Self(pin)
+    }
+}
+
+impl<PIN: InputPin> InputPin for EpdBusy<PIN> {
+    type Error = PIN::Error;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        if EPD_WAIT_DEFERRED.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        self.0.is_high()
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.is_high()?)
+    }
+}
+
+pub(crate) fn defer_epd_wait(defer: bool) {
+    EPD_WAIT_DEFERRED.store(defer, Ordering::Release);
+}
+
+// The panel holds BUSY high while it refreshes.  Reading IDR doesn't need the
+// pin, which the driver owns.
+pub(crate) fn epd_busy() -> bool {
+    unsafe { (*GPIOB::ptr()).idr.read().id7().bit_is_set() }
+}
+
+// The flash chip select (PB6).  There is one `ChipSelect` for it, and the
+// w25q driver and our own flash commands (power-down, SFDP) each get a
+// `FlashCs` handle that borrows it for a pin change.
+pub(crate) type FlashCsCell = RefCell<ChipSelect<PB6<Output<PushPull>>>>;
+
+#[derive(Clone, Copy)]
+pub(crate) struct FlashCs(&'static FlashCsCell);
+
+impl FlashCs {
+    pub(crate) fn new(cell: &'static FlashCsCell) -> Self {
+        Self(cell)
+    }
+}
+
+impl OutputPin for FlashCs {
+    type Error = ChipSelectError<<PB6<Output<PushPull>> as OutputPin>::Error>;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_high()
+    }
+}
@@ -1,4 +1,4 @@
-use cortex_m::prelude::{_embedded_hal_adc_OneShot, _embedded_hal_blocking_delay_DelayMs};
+use cortex_m::prelude::_embedded_hal_adc_OneShot;
 use embedded_graphics::{
     geometry::Point,
     // image::Image,
@@ -10,9 +10,9 @@ use embedded_graphics::{
 };
 use epd_waveshare::epd1in54_v2::Display1in54;
 use int_enum::IntEnum;
+use rtic_monotonics::stm32::ExtU64;
 use stm32l0xx_hal::{
     adc::{Adc, Ready, VRef},
-    delay::Delay,
     gpio::{
         gpioa::{PA0, PA1, PA4},
         Analog, Output, PushPull,
@@ -21,6 +21,8 @@ use stm32l0xx_hal::{
 };
 // use tinybmp::Bmp;
 
+use crate::{errors::Error, Mono};
+
 #[repr(u32)]
 #[derive(PartialEq, PartialOrd, Debug, Clone, Copy, IntEnum)]
 pub(crate) enum VoltageLevels {
@@ -119,24 +121,26 @@ pub(crate) fn draw_charge_icon(charge: &VoltageLevels, display: &mut Display1in5
 //         .unwrap();
 // }
 
-pub(crate) fn read_charge(
+pub(crate) async fn read_charge(
     supercap_read_enable: &mut PA4<Output<PushPull>>,
     supercap_in: &mut PA1<Analog>,
     adc: &mut Adc<Ready>,
-    delay: &mut Delay,
-) -> VoltageLevels {
+) -> Result<VoltageLevels, Error> {
     supercap_read_enable.set_high().ok();
-    delay.delay_ms(50u32);
+    Mono::delay(50u64.millis()).await;
 
     // We are reading very high impedance inputs from the supercap
     // and solar voltage dividers, this is why we need very long sample
     // times to get accurate readings
     adc.set_sample_time(stm32l0xx_hal::adc::SampleTime::T_160_5);
 
-    let vdd = read_vdd(adc);
-    let scap_in: u16 = adc.read(supercap_in).unwrap();
-
+    // Switch the divider back off even if the ADC failed
+    let scap_in = read_vdd(adc).and_then(|vdd| {
+        let scap_in: u16 = adc.read(supercap_in).map_err(|_| Error::Adc)?;
+        Ok((vdd, scap_in))
+    });
     supercap_read_enable.set_low().ok();
+    let (vdd, scap_in) = scap_in?;
 
     let supercap_in = (scap_in as u32 * vdd) / 4095;
     // hprintln!("supercap_in = {}.{:03}V", supercap_in / 1000, supercap_in % 1000).ok();
@@ -147,13 +151,13 @@ pub(crate) fn read_charge(
     // hprintln!("supercap voltage ={}.{:03}V", supercap_voltage/1000, supercap_voltage % 1000).ok();
     let charge = charging_levels(supercap_voltage as u16);
 
-    charge
+    Ok(charge)
 }
 
-fn read_vdd(adc: &mut Adc<Ready>) -> u32 {
+fn read_vdd(adc: &mut Adc<Ready>) -> Result<u32, Error> {
     // Read the V_REFINT ADC channel.  Should be close to the one stored during
     // calibration in VREF_CAL_ADDRESS
-    let vref: u16 = adc.read(&mut VRef).unwrap();
+    let vref: u16 = adc.read(&mut VRef).map_err(|_| Error::Adc)?;
     // Per datasheet, this is where the ADC reading for VREFINT_CAL voltage is stored
     const VREF_CAL_ADDRESS: *mut u16 = 0x1FF8_0078 as *mut u16;
     let vref_cal: u16;
@@ -174,18 +178,21 @@ fn read_vdd(adc: &mut Adc<Ready>) -> u32 {
     let vdd = (VDDA_CARAC * vref_cal as u32) / vref as u32;
     // hprintln!("calculated our Vdd = {}.{:03}V", vdd / 1000, vdd % 1000).ok();
 
-    vdd
+    Ok(vdd)
 }
 
-pub(crate) fn read_solar(solar_in: &mut PA0<Analog>, adc: &mut Adc<Ready>) -> VoltageLevels {
+pub(crate) fn read_solar(
+    solar_in: &mut PA0<Analog>,
+    adc: &mut Adc<Ready>,
+) -> Result<VoltageLevels, Error> {
     // We are reading very high impedance inputs from the supercap
     // and solar voltage dividers, this is why we need very long sample
     // times to get accurate readings
     adc.set_sample_time(stm32l0xx_hal::adc::SampleTime::T_160_5);
 
-    let solar: u16 = adc.read(solar_in).unwrap();
+    let solar: u16 = adc.read(solar_in).map_err(|_| Error::Adc)?;
 
-    let vdd = read_vdd(adc);
+    let vdd = read_vdd(adc)?;
     // hprintln!("solar_adc={}", solar).ok();
     let solar = (solar as u32 * vdd) / 4095;
     // hprintln!("solar_in={}.{:03}V", solar/1000, solar % 1000).ok();
@@ -195,5 +202,5 @@ pub(crate) fn read_solar(solar_in: &mut PA0<Analog>, adc: &mut Adc<Ready>) -> Vo
     // hprintln!("solar={}.{:03}V", solar/1000, solar % 1000).ok();
     let solar = charging_levels(solar as u16);
     // hprintln!("solar={:?}", solar).ok();
-    solar
+    Ok(solar)
 }